@@ -0,0 +1,30 @@
+//! Generates the C header from the C interface of the crate, and checks that
+//! the committed `include/alpaca.h` is up to date. Set `ALPACA_UPDATE_HEADER`
+//! to rewrite it after changing the interface.
+extern crate cbindgen;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=include/alpaca.h");
+    println!("cargo:rerun-if-env-changed=ALPACA_UPDATE_HEADER");
+
+    let config = cbindgen::Config::from_root_or_default(&crate_dir);
+    let bindings = cbindgen::generate_with_config(&crate_dir, config)
+        .unwrap_or_else(|e| panic!("cannot generate alpaca.h: {}", e));
+    let generated = Path::new(&out_dir).join("alpaca.h");
+    bindings.write_to_file(&generated);
+
+    let header = Path::new(&crate_dir).join("include/alpaca.h");
+    if env::var_os("ALPACA_UPDATE_HEADER").is_some() {
+        fs::copy(&generated, &header).unwrap_or_else(|e| panic!("cannot write {}: {}", header.display(), e));
+    } else if fs::read(&generated).ok() != fs::read(&header).ok() {
+        panic!("{} does not match the C interface, rebuild with ALPACA_UPDATE_HEADER=1 to update it", header.display());
+    }
+}
@@ -0,0 +1,481 @@
+#ifndef ALPACA_H
+#define ALPACA_H
+
+/* Generated by cbindgen from the crate sources, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define ALPACA_ERR_DISTRIBUTION 1
+
+#define ALPACA_ERR_SAMPLING 2
+
+#define ALPACA_ERR_CONFIG 3
+
+#define ALPACA_ERR_IO 4
+
+#define ALPACA_ERR_UTF8 5
+
+#define ALPACA_ERR_PADDING_UNDERFLOW 6
+
+#define ALPACA_ERR_TARGET_TOO_LARGE 7
+
+#define ALPACA_ERR_INVALID_ARGUMENT 8
+
+#define ALPACA_ERR_PANIC 9
+
+#define ALPACA_ERR_ALLOC 10
+
+#define ALPACA_ERR_ENCODING 11
+
+// Version of the C interface. Modules must check at startup that
+// `alpaca_abi_version()` returns the version they were compiled against.
+#define ALPACA_ABI_VERSION 4
+
+// Preparsed morphing configuration, shared by all requests (and threads).
+// Opaque to C, constructed with `alpaca_config_new`.
+typedef struct AlpacaConfig AlpacaConfig;
+
+// The padding of an object, generated in chunks. Opaque to C, constructed
+// with `alpaca_pad_reader_new`.
+typedef struct AlpacaPadReader AlpacaPadReader;
+
+// Information about a request. Opaque to C,
+// constructed with `alpaca_morph_info_new` and filled through the
+// `alpaca_morph_info_set_*` functions. Strings are borrowed, they must
+// outlive the morphing call. The warnings of the call are stored back in
+// it, see `alpaca_morph_info_warning`.
+typedef struct MorphInfo MorphInfo;
+
+// An object found by an `AlpacaResolveFn`. `content_type` may be left NULL
+// to keep the kind implied by the html element referencing the object.
+// `css_refs` is the number of `url()` and `@import` references of a
+// stylesheet, for which its target size leaves room; references beyond it
+// may be left unpadded.
+typedef struct AlpacaResolvedObject {
+  size_t size;
+  const char *content_type;
+  size_t css_refs;
+} AlpacaResolvedObject;
+
+// Resolver callback: finds the object at path `reference` (absolute and
+// percent-decoded, without query or fragment) referenced by the page
+// `page_uri` of `http_host`, filling `out`. Returns
+// 1 if found, 0 if the object should not be morphed, negative on error.
+// A panic of the callback fails the morphing call, like a panic of the library.
+typedef int (*AlpacaResolveFn)(void *ctx,
+                               const char *http_host,
+                               const char *page_uri,
+                               const char *reference,
+                               struct AlpacaResolvedObject *out);
+
+// Allocation callback: returns `size` bytes of caller-owned memory, or NULL.
+// Like the resolver callback, it may unwind (eg a panic of a callback written
+// in Rust): the panic is caught and reported like those of the library.
+typedef uint8_t *(*AlpacaAllocFn)(void *ctx, size_t size);
+
+// A buffer returned to C. `data` points to `len` bytes, the fields must not
+// be modified by the caller. Release it with `alpaca_buffer_free`; a
+// `capacity` of 0 means that the memory is not owned by the library (it
+// is empty, or comes from the caller's allocator).
+typedef struct AlpacaBuffer {
+  uint8_t *data;
+  size_t len;
+  size_t capacity;
+} AlpacaBuffer;
+
+// Counters of the object cache, see `alpaca_cache_stats`.
+typedef struct AlpacaCacheStats {
+  uint64_t hits;
+  uint64_t misses;
+  size_t entries;
+} AlpacaCacheStats;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Returns the version of the C interface implemented by the library.
+uint32_t alpaca_abi_version(void);
+
+// Parses and validates a morphing configuration, once at server start. In
+// probabilistic mode (`probabilistic` true) the three distributions are
+// used, otherwise `obj_num`, `obj_size` and `max_obj_size`. Returns NULL if
+// the configuration is invalid, the error is available through
+// `alpaca_last_error_code` and `alpaca_last_error_message`.
+//
+// # Safety
+//
+// In probabilistic mode the distributions must be NUL-terminated strings.
+struct AlpacaConfig *alpaca_config_new(bool probabilistic,
+                                       const char *dist_html_size,
+                                       const char *dist_obj_num,
+                                       const char *dist_obj_size,
+                                       bool use_total_obj_size,
+                                       size_t obj_num,
+                                       size_t obj_size,
+                                       size_t max_obj_size);
+
+// Loads a configuration file (see `Config::load`). Returns NULL if the file
+// cannot be read or is invalid, the error (with the offending line) is
+// available through `alpaca_last_error_code` and `alpaca_last_error_message`.
+//
+// # Safety
+//
+// `path` must be a NUL-terminated string.
+struct AlpacaConfig *alpaca_config_load(const char *path);
+
+// Adds a policy to `config`: requests whose Host matches `host` and whose
+// uri matches `path` are morphed using the default configuration of
+// `policy`, instead of that of `config`. Either pattern may be NULL to match
+// everything, patterns containing `*` or `?` are globs, other paths are
+// prefixes. The most specific matching policy is used.
+//
+// `policy` is consumed (even on failure) and must not be used afterwards.
+// Returns 0 on failure, see `alpaca_last_error_code`.
+//
+// # Safety
+//
+// `config` and `policy` must be distinct configurations returned by
+// `alpaca_config_new` or `alpaca_config_load`, and `config` must not be
+// in use by other threads. `host` and `path` must be NULL or
+// NUL-terminated strings.
+uint8_t alpaca_config_add_policy(struct AlpacaConfig *config,
+                                 const char *host,
+                                 const char *path,
+                                 struct AlpacaConfig *policy);
+
+// Sets a callback resolving the objects referenced by pages, for objects
+// that are not static files under the document root (eg generated or
+// proxied). Passing NULL restores the default resolver, which reads the
+// files under the roots added with `alpaca_config_add_root`, or the one
+// given in `alpaca_morph_info_set_request`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig` not in use by other threads.
+// `resolve` is called concurrently from all threads using `config`, with
+// the given `ctx`; it must be thread-safe, and `ctx` must outlive `config`.
+uint8_t alpaca_config_set_resolver(struct AlpacaConfig *config, AlpacaResolveFn resolve, void *ctx);
+
+// Adds a document root: objects whose uri starts with `prefix` are read from
+// under `path` instead of the root of the request. If `alias` is true the
+// prefix is replaced by `path` (like the `alias` directive of nginx),
+// otherwise the whole uri is appended to it (like `root`). `$http_host` in
+// `path` is replaced by the Host of the request. The longest matching
+// prefix is used. Returns 0 on failure, see `alpaca_last_error_code`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig` not in use by other threads,
+// `prefix` and `path` NUL-terminated strings.
+uint8_t alpaca_config_add_root(struct AlpacaConfig *config,
+                               const char *prefix,
+                               const char *path,
+                               bool alias);
+
+// Sets whether morphed pages are written by editing the original markup in
+// place (keeping its formatting), instead of serializing the parsed page.
+// Pages that cannot be edited in place are still serialized. Returns 0 on
+// failure, see `alpaca_last_error_code`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig` not in use by other threads.
+uint8_t alpaca_config_set_in_place(struct AlpacaConfig *config, bool in_place);
+
+// Sets where the padding of morphed pages is placed: `end` (a comment at
+// the end, the default), `head` (a comment before `</head>`), `body` (a
+// comment before `</body>`), `split` (comments before random tags), `div`
+// (a hidden `<div>` before `</body>`) or `attribute` (a `data-` attribute
+// of the `<body>`). Compressed pages are always padded at their end.
+// Returns 0 on failure, see `alpaca_last_error_code`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig` not in use by other threads,
+// `placement` a NUL-terminated string.
+uint8_t alpaca_config_set_html_padding(struct AlpacaConfig *config, const char *placement);
+
+// Sets the generator of the content of paddings: `random` (bytes from a
+// CSPRNG and alphanumeric text, the default), `alphanumeric`, `mimic` (text
+// made of html and css tokens) or `seeded` (deterministic for a given
+// `seed`, for reproducible tests only). `seed` is ignored by the others.
+// Returns 0 on failure, see `alpaca_last_error_code`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig` not in use by other threads,
+// `name` a NUL-terminated string.
+uint8_t alpaca_config_set_padding_source(struct AlpacaConfig *config,
+                                         const char *name,
+                                         uint64_t seed);
+
+// Frees a configuration returned by `alpaca_config_new` or `alpaca_config_load`.
+//
+// # Safety
+//
+// `config` must be NULL or returned by `alpaca_config_new`, and not used
+// by any thread afterwards.
+void alpaca_config_free(struct AlpacaConfig *config);
+
+// Allocates an empty `MorphInfo`. `abi_version` must be `ALPACA_ABI_VERSION`
+// as seen by the caller, otherwise NULL is returned.
+struct MorphInfo *alpaca_morph_info_new(uint32_t abi_version);
+
+// Frees a `MorphInfo` allocated by `alpaca_morph_info_new`. The content it
+// points to is not freed.
+//
+// # Safety
+//
+// `pinfo` must be NULL or returned by `alpaca_morph_info_new`.
+void alpaca_morph_info_free(struct MorphInfo *pinfo);
+
+// Sets the content to morph (html) or the size of the object to pad
+// (`content` may be NULL in that case). The content may contain NUL bytes,
+// pages are decoded according to `alpaca_morph_info_set_content_type`.
+// Ignored if `pinfo` is NULL; cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be a valid `MorphInfo`, `content` must point to `size` bytes.
+void alpaca_morph_info_set_content(struct MorphInfo *pinfo, const uint8_t *content, size_t size);
+
+// Sets the request information used to locate the objects of an html page.
+// Ignored if `pinfo` is NULL; cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be a valid `MorphInfo`, strings must be NUL-terminated.
+void alpaca_morph_info_set_request(struct MorphInfo *pinfo,
+                                   const char *root,
+                                   const char *uri,
+                                   const char *http_host,
+                                   size_t alias);
+
+// Sets the Content-Type of an html page, whose charset (if any) is the
+// encoding of the content. Otherwise the encoding is taken from the
+// `<meta charset>` of the page, or defaults to UTF-8. The morphed page
+// is returned in the same encoding.
+// Ignored if `pinfo` is NULL; cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be a valid `MorphInfo`, `content_type` must be NULL or a
+// NUL-terminated string.
+void alpaca_morph_info_set_content_type(struct MorphInfo *pinfo, const char *content_type);
+
+// Sets the query and content type of an object request.
+// Ignored if `pinfo` is NULL; cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be a valid `MorphInfo`, strings must be NUL-terminated.
+void alpaca_morph_info_set_object(struct MorphInfo *pinfo,
+                                  const char *query,
+                                  const char *content_type);
+
+// Sets the Content-Encoding negotiated with the client (`gzip`, `deflate`
+// or `br`). The morphing functions then return the content compressed,
+// and the target sizes are those of the compressed content. `morph_object`
+// needs the whole object, set with `alpaca_morph_info_set_content`, and
+// returns it padded instead of the padding only. NULL or `identity`
+// (the default) disables compression.
+// Ignored if `pinfo` is NULL; cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be a valid `MorphInfo`, `content_encoding` must be NULL or a
+// NUL-terminated string.
+void alpaca_morph_info_set_compression(struct MorphInfo *pinfo, const char *content_encoding);
+
+// Sets an allocator for the output buffers. When set, morphed pages and
+// paddings are copied into memory obtained by `alloc(ctx, size)` (eg from
+// a server pool) which the library never frees. Passing NULL restores the
+// default (library-owned buffers, freed by `alpaca_buffer_free`).
+// Ignored if `pinfo` is NULL; cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be a valid `MorphInfo`, `alloc` must return NULL or a
+// pointer to at least `size` writable bytes.
+void alpaca_morph_info_set_allocator(struct MorphInfo *pinfo, AlpacaAllocFn alloc, void *ctx);
+
+// Returns the number of warnings of the last `morph_html` or `morph_css`
+// call with `pinfo`: problems that did not prevent morphing, eg objects
+// that could not be resolved or padded and were left unchanged. Returns 0
+// if `pinfo` is NULL; cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be NULL or a valid `MorphInfo`.
+size_t alpaca_morph_info_warning_count(const struct MorphInfo *pinfo);
+
+// Returns the message of warning `i` of the last morphing call, see
+// `alpaca_morph_info_warning_count`, or NULL if there is no such warning.
+// The string is owned by `pinfo`, it is valid until the next morphing
+// call with `pinfo` or until it is freed. Cannot panic.
+//
+// # Safety
+//
+// `pinfo` must be NULL or a valid `MorphInfo`.
+const char *alpaca_morph_info_warning(const struct MorphInfo *pinfo, size_t i);
+
+// It samples a new page using the morphing mode of `config`, changes the
+// references to its objects accordingly, and pads it. Objects that cannot
+// be morphed are left unchanged, see `alpaca_morph_info_warning_count`.
+//
+// Returns 1 on success, in which case the morphed page is stored in `out`.
+// Returns 0 on failure (including a panic inside the library), leaving
+// `out` untouched; the error is available through `alpaca_last_error_code`
+// and `alpaca_last_error_message`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+// `out` must point to an `AlpacaBuffer`.
+uint8_t morph_html(const struct AlpacaConfig *config,
+                   const struct MorphInfo *pinfo,
+                   struct AlpacaBuffer *out);
+
+// Returns the object's padding, or the whole padded object if a
+// compression is set (see `alpaca_morph_info_set_compression`). Images
+// and fonts are padded at their end, which strict decoders (eg of WOFF
+// fonts) may reject; see `morph_object_content`. Compressed images and
+// fonts cannot be padded.
+//
+// Returns 1 on success, in which case the padding is stored in `out`.
+// Returns 0 on failure (including a panic inside the library), leaving
+// `out` untouched; the error is available through `alpaca_last_error_code`
+// and `alpaca_last_error_message`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+// `out` must point to an `AlpacaBuffer`.
+uint8_t morph_object(const struct AlpacaConfig *config,
+                     const struct MorphInfo *pinfo,
+                     struct AlpacaBuffer *out);
+
+// Like `morph_object`, returning the whole padded object, set with
+// `alpaca_morph_info_set_content`. The padding of PNG, JPEG, GIF and WebP
+// images, fonts and PDFs is inserted in their container (as an ancillary
+// chunk, comment, private data or table), so that they remain valid;
+// other objects are padded at their end. Compressed objects (see
+// `alpaca_morph_info_set_compression`) are padded at their end, except
+// images and fonts, which cannot be padded once compressed.
+//
+// Returns 1 on success, in which case the object is stored in `out`.
+// Returns 0 on failure, leaving `out` untouched; see `morph_object`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+// `out` must point to an `AlpacaBuffer`.
+uint8_t morph_object_content(const struct AlpacaConfig *config,
+                             const struct MorphInfo *pinfo,
+                             struct AlpacaBuffer *out);
+
+// Like `morph_object`, returning a reader of the padding instead of the
+// whole padding: `alpaca_pad_reader_read` generates it in chunks of the
+// caller's size, directly in the caller's buffers (eg those of the
+// response body), so that large paddings are never held in memory.
+// Compressed objects cannot be read this way.
+//
+// Returns NULL on failure, the error is available through
+// `alpaca_last_error_code` and `alpaca_last_error_message`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`.
+// The reader does not borrow them, it may outlive both.
+struct AlpacaPadReader *alpaca_pad_reader_new(const struct AlpacaConfig *config,
+                                              const struct MorphInfo *pinfo);
+
+// Writes the next (at most `len`) bytes of the padding to `buf`. Returns
+// the number of bytes written, less than `len` only at the end of the
+// padding, 0 when it has been read entirely (or on failure).
+//
+// # Safety
+//
+// `reader` must be a valid `AlpacaPadReader` not in use by other threads,
+// `buf` must point to `len` writable bytes.
+size_t alpaca_pad_reader_read(struct AlpacaPadReader *reader, uint8_t *buf, size_t len);
+
+// Returns the number of bytes of the padding left to read, 0 if `reader`
+// is NULL. Cannot panic.
+//
+// # Safety
+//
+// `reader` must be NULL or a valid `AlpacaPadReader`.
+size_t alpaca_pad_reader_remaining(const struct AlpacaPadReader *reader);
+
+// Frees a reader returned by `alpaca_pad_reader_new`.
+//
+// # Safety
+//
+// `reader` must be NULL or returned by `alpaca_pad_reader_new`, and not
+// used afterwards.
+void alpaca_pad_reader_free(struct AlpacaPadReader *reader);
+
+// Morphs a stylesheet, set with `alpaca_morph_info_set_content`: the
+// references to its objects (`url()`, `@import`) are changed like those of
+// html pages, and it is padded to the target size of the query. The request
+// fields are those of the stylesheet, and `query` must be set with
+// `alpaca_morph_info_set_object`. Unlike `morph_object`, the whole morphed
+// stylesheet is returned, to be served instead of the original.
+//
+// Returns 1 on success, in which case the stylesheet is stored in `out`.
+// Returns 0 on failure, leaving `out` untouched; see `morph_html`.
+//
+// # Safety
+//
+// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+// `out` must point to an `AlpacaBuffer`.
+uint8_t morph_css(const struct AlpacaConfig *config,
+                  const struct MorphInfo *pinfo,
+                  struct AlpacaBuffer *out);
+
+// Frees a buffer returned by the library and resets it to empty. Buffers
+// placed in caller-allocated memory (`capacity` 0) are only reset.
+//
+// # Safety
+//
+// `buf` must be NULL or point to an `AlpacaBuffer` filled by the library.
+void alpaca_buffer_free(struct AlpacaBuffer *buf);
+
+// Drops the cached size of the object file `path` (an absolute path, of the
+// file or of a symlink to it), or of all objects if `path` is NULL.
+// Cached entries are also dropped automatically when the files change.
+//
+// # Safety
+//
+// `path` must be NULL or a NUL-terminated string.
+void alpaca_cache_invalidate(const char *path);
+
+// Stores the hit/miss counters and the number of entries of the object
+// cache in `out`. Returns 0 if `out` is NULL.
+//
+// # Safety
+//
+// `out` must be NULL or point to an `AlpacaCacheStats`.
+uint8_t alpaca_cache_stats(struct AlpacaCacheStats *out);
+
+// Returns the code of the last error that occurred in the calling thread,
+// or 0 if the last call succeeded. See `AlpacaError::code`. Cannot panic.
+int alpaca_last_error_code(void);
+
+// Returns the message of the last error that occurred in the calling
+// thread, or NULL if the last call succeeded. The string is owned by the
+// library and is valid until the next call into it from the same thread.
+// Cannot panic.
+const char *alpaca_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ALPACA_H */
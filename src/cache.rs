@@ -0,0 +1,137 @@
+//! Process-wide cache of the objects resolved from the filesystem, so that
+//! pages don't stat every referenced file on each request.
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use resolver::ResolvedObject;
+
+/// Cached entries are trusted for this long, then the file is checked for
+/// changes (of mtime, inode or size) on its next lookup.
+pub const REVALIDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum number of cached files, the cache is emptied when exceeded.
+pub const MAX_ENTRIES: usize = 100000;
+
+/// Counters of the cache, for monitoring.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+// Identifies a version of a file, any change invalidates the entry.
+#[derive(Clone, Copy, PartialEq)]
+struct FileId {
+    len: u64,
+    mtime: Option<SystemTime>,
+    dev: u64,
+    ino: u64,
+}
+
+struct Entry {
+    object: ResolvedObject,
+    id: FileId,
+    checked: Instant,
+}
+
+/// Resolved objects keyed by their absolute path, shared by all threads.
+#[derive(Default)]
+pub struct ObjectCache {
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ObjectCache {
+    pub fn new() -> ObjectCache {
+        ObjectCache::default()
+    }
+
+    /// The cache used by `FsResolver`.
+    pub fn global() -> &'static ObjectCache {
+        static CACHE: OnceLock<ObjectCache> = OnceLock::new();
+        CACHE.get_or_init(ObjectCache::new)
+    }
+
+    /// Returns the cached object of `path`, if the file has not changed.
+    pub fn get(&self, path: &Path) -> Option<ResolvedObject> {
+        let (object, id, fresh) = {
+            let entries = self.lock();
+            let entry = match entries.get(path) {
+                Some(entry) => entry,
+                None => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                },
+            };
+            (entry.object.clone(), entry.id, entry.checked.elapsed() < REVALIDATE_INTERVAL)
+        };
+
+        // the file is checked without holding the lock, the entry may have
+        // been replaced meanwhile and is only updated if it has not
+        let unchanged = fresh || fs::metadata(path).is_ok_and(|meta| file_id(&meta) == id);
+        if !fresh {
+            let mut entries = self.lock();
+            if entries.get(path).is_some_and(|entry| entry.id == id) {
+                if unchanged {
+                    entries.get_mut(path).unwrap().checked = Instant::now();
+                } else {
+                    entries.remove(path);
+                }
+            }
+        }
+
+        if unchanged {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            Some(object)
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    /// Caches the object resolved from the file `path` with metadata `meta`.
+    pub fn insert(&self, path: &Path, object: &ResolvedObject, meta: &Metadata) {
+        let mut entries = self.lock();
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+        entries.insert(PathBuf::from(path), Entry { object: object.clone(), id: file_id(meta), checked: Instant::now() });
+    }
+
+    /// Drops the entry of `path`, or all entries if None.
+    pub fn invalidate(&self, path: Option<&Path>) {
+        let mut entries = self.lock();
+        match path {
+            Some(path) => { entries.remove(path); },
+            None => entries.clear(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lock().len(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, Entry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    FileId { len: meta.len(), mtime: meta.modified().ok(), dev: meta.dev(), ino: meta.ino() }
+}
+
+#[cfg(not(unix))]
+fn file_id(meta: &Metadata) -> FileId {
+    FileId { len: meta.len(), mtime: meta.modified().ok(), dev: 0, ino: 0 }
+}
@@ -0,0 +1,105 @@
+//! Detects the character encoding of html pages, which are morphed as
+//! UTF-8 and written back in their original encoding.
+use std::borrow::Cow;
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1252, X_USER_DEFINED};
+use dom;
+use error::AlpacaError;
+
+// Number of bytes searched for a <meta> declaring the encoding, like browsers.
+const PRESCAN_SIZE: usize = 1024;
+
+/// The encoding of a page, and whether it starts with a byte order mark.
+#[derive(Clone, Copy)]
+pub struct Charset {
+    encoding: &'static Encoding,
+    bom: usize,
+}
+
+impl Default for Charset {
+    fn default() -> Charset {
+        Charset { encoding: UTF_8, bom: 0 }
+    }
+}
+
+impl Charset {
+    /// Detects the encoding of `content`, in the order of browsers: its byte
+    /// order mark, the charset of its `content_type` (the Content-Type
+    /// header), or its `<meta charset>`. Defaults to UTF-8.
+    pub fn detect(content: &[u8], content_type: Option<&str>) -> Result<Charset, AlpacaError> {
+        let charset = match Encoding::for_bom(content) {
+            Some((encoding, bom)) => Charset { encoding, bom },
+            None => {
+                let encoding = content_type.and_then(content_type_encoding)
+                    .or_else(|| meta_encoding(content))
+                    .unwrap_or(UTF_8);
+                Charset { encoding, bom: 0 }
+            },
+        };
+
+        // the padding and the rewritten uris are ASCII, the content must stay so
+        if charset.encoding.output_encoding() != charset.encoding {
+            return Err(AlpacaError::Encoding(String::from(charset.encoding.name())));
+        }
+        Ok(charset)
+    }
+
+    /// The canonical name of the encoding (eg "Shift_JIS").
+    pub fn name(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    /// Decodes `content`, without its byte order mark. Malformed sequences
+    /// are replaced by U+FFFD, like browsers do.
+    pub fn decode<'a>(&self, content: &'a [u8]) -> Cow<'a, str> {
+        self.encoding.decode_without_bom_handling(&content[self.bom..]).0
+    }
+
+    /// Encodes the (morphed) `html`, with the byte order mark of the original
+    /// content. Characters that the encoding lacks become character references.
+    pub fn encode(&self, html: Vec<u8>) -> Result<Vec<u8>, AlpacaError> {
+        if self.encoding == UTF_8 && self.bom == 0 {
+            return Ok(html);
+        }
+        let html = String::from_utf8(html).map_err(|e| e.utf8_error())?;
+        let mut out = Vec::with_capacity(self.bom + html.len());
+        if self.bom > 0 {
+            out.extend_from_slice(&[0xEF, 0xBB, 0xBF]);     // only UTF-8 marks are ASCII-compatible
+        }
+        out.extend_from_slice(&self.encoding.encode(&html).0);
+        Ok(out)
+    }
+}
+
+// The encoding of the charset parameter of a Content-Type.
+fn content_type_encoding(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("charset") {
+            return None;
+        }
+        Encoding::for_label(value.trim().trim_matches(|c| c == '"' || c == '\'').as_bytes())
+    })
+}
+
+// The encoding declared by a <meta charset> or <meta http-equiv="Content-Type">
+// at the start of the page. Only ASCII matters, so the bytes are read as UTF-8.
+fn meta_encoding(content: &[u8]) -> Option<&'static Encoding> {
+    let start = String::from_utf8_lossy(&content[..content.len().min(PRESCAN_SIZE)]);
+    let document = dom::parse_html(&start);
+
+    let encoding = document.select("meta").unwrap().find_map(|meta| {
+        let attrs = meta.attributes.borrow();
+        match (attrs.get("charset"), attrs.get("http-equiv"), attrs.get("content")) {
+            (Some(charset), _, _) => Encoding::for_label(charset.as_bytes()),
+            (None, Some(equiv), Some(content)) if equiv.eq_ignore_ascii_case("content-type") => content_type_encoding(content),
+            _ => None,
+        }
+    })?;
+
+    // the page was read as ASCII, so it cannot be UTF-16
+    Some(match encoding {
+        e if e == UTF_16BE || e == UTF_16LE => UTF_8,
+        e if e == X_USER_DEFINED => WINDOWS_1252,
+        e => e,
+    })
+}
@@ -0,0 +1,228 @@
+//! Compresses morphed content so that its encoded size (on the wire) hits
+//! the target size exactly. The content is compressed and flushed to a byte
+//! boundary, then the padding follows in uncompressed (stored) blocks, whose
+//! size is known in advance whatever the padding bytes are.
+use std::io::Write;
+use brotli::CompressorWriter;
+use crc32fast::{self, Hasher};
+use flate2::Compression as Level;
+use flate2::write::DeflateEncoder;
+use error::AlpacaError;
+
+/// Maximum length of a deflate stored block.
+const DEFLATE_BLOCK: usize = 65535;
+/// Length of the header of a deflate stored block (at a byte boundary).
+const DEFLATE_BLOCK_HEADER: usize = 5;
+/// Maximum length of a brotli uncompressed meta-block with a 3-byte header,
+/// which cannot be empty.
+const BROTLI_BLOCK: usize = 1 << 20;
+const BROTLI_BLOCK_HEADER: usize = 3;
+
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x9c];
+
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW: u32 = 22;
+
+/// A Content-Encoding produced by the library.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compression {
+    /// `gzip`
+    Gzip,
+    /// `deflate` (a zlib stream, as used by HTTP)
+    Deflate,
+    /// `br`
+    Brotli,
+}
+
+impl Compression {
+    /// The compression of a Content-Encoding, None for `identity`.
+    pub fn from_content_encoding(encoding: &str) -> Result<Option<Compression>, AlpacaError> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(None),
+            "gzip" | "x-gzip" => Ok(Some(Compression::Gzip)),
+            "deflate" => Ok(Some(Compression::Deflate)),
+            "br" => Ok(Some(Compression::Brotli)),
+            other => Err(AlpacaError::InvalidArgument(format!("unsupported Content-Encoding \"{}\"", other.escape_default()))),
+        }
+    }
+
+    pub fn content_encoding(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gzip",
+            Compression::Deflate => "deflate",
+            Compression::Brotli => "br",
+        }
+    }
+
+    /// Compresses `content`, to be padded later (possibly after checking the
+    /// sizes it can be padded to), see `Compressed`.
+    pub fn compress(&self, content: &[u8]) -> Result<Compressed, AlpacaError> {
+        let checksum = match *self {
+            Compression::Gzip => crc32fast::hash(content),
+            Compression::Deflate => adler32(1, content),
+            Compression::Brotli => 0,
+        };
+        Ok(Compressed { compression: *self, data: self.compressed(content)?, checksum, len: content.len() })
+    }
+
+    /// The smallest encoded size of `content` followed by `min_padding`
+    /// bytes of padding, ie the smallest target size it can be padded to.
+    pub fn min_size(&self, content: &[u8], min_padding: usize) -> Result<usize, AlpacaError> {
+        Ok(self.compress(content)?.min_size(min_padding))
+    }
+
+    /// Compresses `content` followed by padding, so that the output is
+    /// exactly `target_size` bytes, see `Compressed::pad`.
+    pub fn compress_padded<F>(&self, content: &[u8], target_size: usize, padding: F) -> Result<Vec<u8>, AlpacaError>
+        where F: FnOnce(usize) -> Result<Vec<u8>, AlpacaError>
+    {
+        self.compress(content)?.pad(target_size, padding)
+    }
+
+    // The header and the compressed content, flushed to a byte boundary
+    // without ending the stream.
+    fn compressed(&self, content: &[u8]) -> Result<Vec<u8>, AlpacaError> {
+        let io_error = |e| AlpacaError::Io(String::from("compressed content"), e);
+        match *self {
+            Compression::Gzip | Compression::Deflate => {
+                let header: &[u8] = if *self == Compression::Gzip { &GZIP_HEADER } else { &ZLIB_HEADER };
+                let mut encoder = DeflateEncoder::new(Vec::from(header), Level::default());
+                encoder.write_all(content).map_err(io_error)?;
+                encoder.flush().map_err(io_error)?;     // a sync flush, ending with an empty stored block
+                Ok(encoder.get_ref().clone())
+            },
+            Compression::Brotli => {
+                let mut encoder = CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(content).map_err(io_error)?;
+                encoder.flush().map_err(io_error)?;
+                Ok(encoder.get_ref().clone())
+            },
+        }
+    }
+
+    // Maximum length and header length of the uncompressed blocks.
+    fn block(&self) -> (usize, usize) {
+        match *self {
+            Compression::Brotli => (BROTLI_BLOCK, BROTLI_BLOCK_HEADER),
+            _ => (DEFLATE_BLOCK, DEFLATE_BLOCK_HEADER),
+        }
+    }
+
+    // Minimum length of a block.
+    fn min_block(&self) -> usize {
+        if *self == Compression::Brotli { 1 } else { 0 }
+    }
+
+    // Bytes after the last block: the checksum, or the last brotli meta-block.
+    fn trailer_size(&self) -> usize {
+        match *self {
+            Compression::Gzip => 8,
+            Compression::Deflate => 4,
+            Compression::Brotli => 1,
+        }
+    }
+
+    fn push_block(&self, out: &mut Vec<u8>, data: &[u8], last: bool) {
+        match *self {
+            Compression::Gzip | Compression::Deflate => {
+                // BFINAL and BTYPE 00 (stored), then LEN and NLEN
+                out.push(last as u8);
+                out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                out.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+            },
+            Compression::Brotli => {
+                // ISLAST 0, MNIBBLES (4 or 5), MLEN - 1 and ISUNCOMPRESSED, in 20 or 24 bits
+                let mlen = data.len() as u32 - 1;
+                let bits = if mlen < 1 << 16 {
+                    (mlen << 3) | (1 << 19)
+                } else {
+                    (1 << 1) | (mlen << 3) | (1 << 23)
+                };
+                out.extend_from_slice(&bits.to_le_bytes()[..3]);
+            },
+        }
+        out.extend_from_slice(data);
+    }
+}
+
+/// Content compressed and flushed to a byte boundary, without ending the
+/// stream, so that padding can follow.
+pub struct Compressed {
+    compression: Compression,
+    data: Vec<u8>,
+    // crc32 (gzip) or adler32 (deflate) of the content
+    checksum: u32,
+    len: usize,
+}
+
+impl Compressed {
+    /// The smallest encoded size of the content followed by `min_padding`
+    /// bytes of padding, ie the smallest target size it can be padded to.
+    pub fn min_size(&self, min_padding: usize) -> usize {
+        let (block, header) = self.compression.block();
+        let min_padding = min_padding.max(self.compression.min_block());
+        let blocks = min_padding.div_ceil(block).max(1);
+        self.data.len() + blocks * header + min_padding + self.compression.trailer_size()
+    }
+
+    /// Appends padding and ends the stream, so that the output is exactly
+    /// `target_size` bytes. `padding(len)` must return `len` bytes, that are
+    /// appended to the (uncompressed) content.
+    pub fn pad<F>(self, target_size: usize, padding: F) -> Result<Vec<u8>, AlpacaError>
+        where F: FnOnce(usize) -> Result<Vec<u8>, AlpacaError>
+    {
+        let compression = self.compression;
+        let mut out = self.data;
+        let (block, header) = compression.block();
+
+        // the blocks have a fixed header, and (for brotli) cannot be empty
+        let min_size = out.len() + header + compression.min_block() + compression.trailer_size();
+        if target_size < min_size {
+            return Err(AlpacaError::PaddingUnderflow { size: min_size, target_size });
+        }
+        let available = target_size - out.len() - compression.trailer_size();
+        let blocks = available.div_ceil(block + header).max(1);
+        let pad = padding(available - blocks * header)?;
+        if pad.len() != available - blocks * header {
+            return Err(AlpacaError::PaddingUnderflow { size: out.len() + blocks * header + pad.len() + compression.trailer_size(), target_size });
+        }
+
+        // spread the padding evenly over the blocks
+        let mut start = 0;
+        for i in 0..blocks {
+            let end = pad.len() * (i + 1) / blocks;
+            compression.push_block(&mut out, &pad[start..end], i + 1 == blocks);
+            start = end;
+        }
+
+        match compression {
+            Compression::Gzip => {
+                let mut hasher = Hasher::new_with_initial_len(self.checksum, self.len as u64);
+                hasher.update(&pad);
+                out.extend_from_slice(&hasher.finalize().to_le_bytes());
+                out.extend_from_slice(&((self.len + pad.len()) as u32).to_le_bytes());
+            },
+            Compression::Deflate => {
+                out.extend_from_slice(&adler32(self.checksum, &pad).to_be_bytes());
+            },
+            Compression::Brotli => out.push(0x03),    // ISLAST, ISLASTEMPTY
+        }
+        debug_assert_eq!(out.len(), target_size);
+        Ok(out)
+    }
+}
+
+fn adler32(adler: u32, data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,413 @@
+//! Typed morphing configuration, and its loader from TOML files.
+use std::{fs, iter};
+use std::path::Path;
+use toml;
+use toml::Spanned;
+use distribution::Dist;
+use policy::{Pattern, Policy, PolicyRouter};
+use resolver::DocumentRoot;
+use error::AlpacaError;
+use pad::MIN_HTML_PADDING;
+
+/// Default maximum target size of objects, larger sizes most likely come
+/// from a wrong configuration or a bug.
+pub const DEFAULT_MAX_TARGET_SIZE: usize = 1000000000;
+
+/// Parameters of probabilistic morphing.
+pub struct ProbabilisticConfig {
+    /// Distribution of the html size
+    pub dist_html_size: Dist,
+    /// Distribution of the number of objects
+    pub dist_obj_num: Dist,
+    /// Distribution of the size of each object (or of their total size)
+    pub dist_obj_size: Dist,
+    /// Sample the total size of all objects instead of each one separately
+    pub use_total_obj_size: bool,
+}
+
+/// Parameters of deterministic morphing.
+pub struct DeterministicConfig {
+    /// The number of objects is padded to a multiple of this
+    pub obj_num: usize,
+    /// Object (and html) sizes are padded to a multiple of this
+    pub obj_size: usize,
+    /// Maximum size of the fake padding objects
+    pub max_obj_size: usize,
+}
+
+/// The morphing mode, together with its parameters.
+pub enum MorphConfig {
+    Probabilistic(ProbabilisticConfig),
+    Deterministic(DeterministicConfig),
+}
+
+/// Limits applied to all requests.
+pub struct Limits {
+    /// Objects are never padded to more than this size
+    pub max_target_size: usize,
+}
+
+/// Where the padding of html pages is placed.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum HtmlPadding {
+    /// A comment at the end of the page
+    #[default]
+    End,
+    /// A comment at the end of the `<head>`
+    Head,
+    /// A comment before `</body>`
+    Body,
+    /// Several comments, before random tags of the page
+    Split,
+    /// A hidden `<div>` before `</body>`
+    Div,
+    /// A `data-` attribute of the `<body>`
+    Attribute,
+}
+
+impl HtmlPadding {
+    /// The placement of a name, as in the `padding` key of the `[html]` section.
+    pub fn from_name(name: &str) -> Option<HtmlPadding> {
+        Some(match name {
+            "end" => HtmlPadding::End,
+            "head" => HtmlPadding::Head,
+            "body" => HtmlPadding::Body,
+            "split" => HtmlPadding::Split,
+            "div" => HtmlPadding::Div,
+            "attribute" => HtmlPadding::Attribute,
+            _ => return None,
+        })
+    }
+}
+
+/// How morphed html pages are written.
+#[derive(Default)]
+pub struct HtmlConfig {
+    /// Edit the original markup in place, instead of serializing the parsed
+    /// page (see `inplace`)
+    pub in_place: bool,
+    /// Where the padding is placed
+    pub padding: HtmlPadding,
+}
+
+/// A complete configuration, as loaded from a configuration file.
+pub struct Config {
+    /// The default morphing configuration
+    pub morph: MorphConfig,
+    /// Per-host and per-path policies, overriding the default
+    pub policies: PolicyRouter,
+    /// Document roots of the objects, see `FsResolver`
+    pub roots: Vec<DocumentRoot>,
+    pub html: HtmlConfig,
+    pub limits: Limits,
+}
+
+impl MorphConfig {
+    /// Checks that the parameters are consistent, so that errors are
+    /// reported when the configuration is loaded instead of on each request.
+    pub fn validate(&self) -> Result<(), AlpacaError> {
+        match *self {
+            MorphConfig::Probabilistic(ref config) => config.validate(),
+            MorphConfig::Deterministic(ref config) => config.validate(),
+        }
+    }
+}
+
+impl ProbabilisticConfig {
+    fn validate(&self) -> Result<(), AlpacaError> {
+        let joint = self.dist_obj_size.name == "Joint";
+        if joint && !self.use_total_obj_size {
+            return Err(AlpacaError::Config(String::from("a Joint dist_obj_size requires use_total_obj_size")));
+        }
+
+        // custom distributions need 1 value per row, 2 for a joint html/obj size
+        let expected = [
+            ("dist_html_size", &self.dist_html_size, if joint { 2 } else { 1 }),
+            ("dist_obj_num", &self.dist_obj_num, 1),
+            ("dist_obj_size", &self.dist_obj_size, 1),
+        ];
+        for &(name, dist, columns) in expected.iter() {
+            match dist.values {
+                Some(ref values) if values[0].len() != columns => {
+                    return Err(AlpacaError::Config(format!("{} contains {} values per row, expected {}", name, values[0].len(), columns)));
+                },
+                None if joint && name == "dist_html_size" => {
+                    return Err(AlpacaError::Config(String::from("joint distributions need to be given in a file")));
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeterministicConfig {
+    fn validate(&self) -> Result<(), AlpacaError> {
+        if self.obj_num == 0 || self.obj_size == 0 {
+            return Err(AlpacaError::Config(String::from("obj_num and obj_size must be positive")));
+        }
+        if self.obj_size > self.max_obj_size || !self.max_obj_size.is_multiple_of(self.obj_size) {
+            return Err(AlpacaError::Config(format!("max_obj_size ({}) must be greater-or-equal and a multiple of obj_size ({})", self.max_obj_size, self.obj_size)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { max_target_size: DEFAULT_MAX_TARGET_SIZE }
+    }
+}
+
+impl Config {
+    /// A configuration using `morph` for all requests.
+    pub fn new(morph: MorphConfig) -> Config {
+        Config { morph, policies: PolicyRouter::new(), roots: Vec::new(), html: HtmlConfig::default(), limits: Limits::default() }
+    }
+
+    /// Loads a configuration file. Relative `.dist` paths are resolved
+    /// against the directory of the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, AlpacaError> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path).map_err(|e| AlpacaError::Io(path.display().to_string(), e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        Config::parse(&data, &path.display().to_string(), dir)
+    }
+
+    /// Parses a configuration from a TOML string. `dir` is used to resolve
+    /// relative `.dist` paths, `name` only appears in error messages.
+    pub fn parse(data: &str, name: &str, dir: &Path) -> Result<Config, AlpacaError> {
+        let parser = Parser { data, name, dir };
+
+        let raw: RawConfig = toml::from_str(data).map_err(|e|
+            parser.error(e.span().map_or(0, |span| span.start), e.message())
+        )?;
+        parser.config(raw)
+    }
+
+    /// Returns the morphing configuration to use for the given request.
+    pub fn morph_config_for(&self, http_host: &str, uri: &str) -> &MorphConfig {
+        self.policies.select(http_host, uri).map_or(&self.morph, |p| &p.morph)
+    }
+
+    /// Checks all morphing configurations, see `MorphConfig::validate`,
+    /// and the limits.
+    pub fn validate(&self) -> Result<(), AlpacaError> {
+        self.morph.validate()?;
+        for p in self.policies.policies() {
+            let name = format!("{}{}", p.host.as_ref().map_or("", Pattern::as_str), p.path.as_ref().map_or("", Pattern::as_str));
+            p.morph.validate().map_err(|e| AlpacaError::Config(format!("policy {}: {}", name, config_message(e))))?;
+        }
+        self.validate_limits()
+    }
+
+    // The maximum target size must allow the smallest page (an empty one
+    // with its padding) and the largest deterministic padding objects.
+    fn validate_limits(&self) -> Result<(), AlpacaError> {
+        let min_target_size = iter::once(&self.morph).chain(self.policies.policies().iter().map(|p| &p.morph))
+            .map(|morph| match *morph {
+                MorphConfig::Deterministic(ref config) => config.max_obj_size,
+                MorphConfig::Probabilistic(_) => 0,
+            })
+            .fold(MIN_HTML_PADDING + 1, usize::max);
+
+        if self.limits.max_target_size < min_target_size {
+            return Err(AlpacaError::Config(format!("max_target_size ({}) must be at least {}", self.limits.max_target_size, min_target_size)));
+        }
+        Ok(())
+    }
+}
+
+// The schema of the configuration file. Spans are kept to report the line
+// of invalid values.
+//
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    mode: Spanned<String>,
+    probabilistic: Option<Spanned<RawProbabilistic>>,
+    deterministic: Option<Spanned<RawDeterministic>>,
+    html: Option<RawHtml>,
+    limits: Option<RawLimits>,
+    #[serde(default, rename = "override")]
+    overrides: Vec<Spanned<RawOverride>>,
+    #[serde(default, rename = "root")]
+    roots: Vec<Spanned<RawRoot>>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct RawProbabilistic {
+    dist_html_size: Spanned<String>,
+    dist_obj_num: Spanned<String>,
+    dist_obj_size: Spanned<String>,
+    #[serde(default)]
+    use_total_obj_size: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct RawDeterministic {
+    obj_num: usize,
+    obj_size: usize,
+    max_obj_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHtml {
+    #[serde(default)]
+    in_place: bool,
+    padding: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLimits {
+    max_target_size: Spanned<usize>,
+}
+
+// Overrides apply to a host and/or path pattern, and inherit the mode (and
+// the parameters of the mode) that they don't specify from the top-level
+// configuration.
+//
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOverride {
+    host: Option<String>,
+    path: Option<String>,
+    mode: Option<Spanned<String>>,
+    probabilistic: Option<Spanned<RawProbabilistic>>,
+    deterministic: Option<Spanned<RawDeterministic>>,
+}
+
+// A document root, exactly one of root and alias must be given.
+//
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRoot {
+    prefix: String,
+    root: Option<String>,
+    alias: Option<String>,
+}
+
+struct Parser<'a> {
+    data: &'a str,
+    name: &'a str,
+    dir: &'a Path,
+}
+
+impl<'a> Parser<'a> {
+    fn config(&self, raw: RawConfig) -> Result<Config, AlpacaError> {
+        let morph = self.morph(&raw.mode, &raw.probabilistic, &raw.deterministic, raw.mode.span().start)?;
+
+        let mut policies = PolicyRouter::new();
+        for o in &raw.overrides {
+            let start = o.span().start;
+            let o = o.get_ref();
+            if o.host.is_none() && o.path.is_none() {
+                return Err(self.error(start, "override needs a host or a path"));
+            }
+            let mode = o.mode.as_ref().unwrap_or(&raw.mode);
+            let probabilistic = if o.probabilistic.is_some() { &o.probabilistic } else { &raw.probabilistic };
+            let deterministic = if o.deterministic.is_some() { &o.deterministic } else { &raw.deterministic };
+
+            policies.add(Policy {
+                host: o.host.as_ref().map(|h| Pattern::new(h)),
+                path: o.path.as_ref().map(|p| Pattern::new(p)),
+                morph: self.morph(mode, probabilistic, deterministic, start)?,
+            });
+        }
+
+        let mut roots = Vec::new();
+        for r in &raw.roots {
+            let (path, alias) = match *r.get_ref() {
+                RawRoot { root: Some(ref path), alias: None, .. } => (path, false),
+                RawRoot { root: None, alias: Some(ref path), .. } => (path, true),
+                _ => return Err(self.error(r.span().start, "a root needs exactly one of root and alias")),
+            };
+            roots.push(DocumentRoot { prefix: r.get_ref().prefix.clone(), path: path.clone(), alias });
+        }
+
+        let html = match raw.html {
+            Some(h) => HtmlConfig {
+                in_place: h.in_place,
+                padding: match h.padding {
+                    Some(p) => HtmlPadding::from_name(p.get_ref()).ok_or_else(|| self.error(p.span().start, &format!("unknown html padding \"{}\"", p.get_ref())))?,
+                    None => HtmlPadding::default(),
+                },
+            },
+            None => HtmlConfig::default(),
+        };
+        let limits = raw.limits.as_ref().map_or_else(Limits::default, |l| Limits { max_target_size: *l.max_target_size.get_ref() });
+        let config = Config { morph, policies, roots, html, limits };
+        if let Some(ref l) = raw.limits {
+            config.validate_limits().map_err(|e| self.error(l.max_target_size.span().start, &config_message(e)))?;
+        }
+        Ok(config)
+    }
+
+    // Builds and validates the MorphConfig of a section starting at `start`.
+    fn morph(
+        &self,
+        mode: &Spanned<String>,
+        probabilistic: &Option<Spanned<RawProbabilistic>>,
+        deterministic: &Option<Spanned<RawDeterministic>>,
+        start: usize,
+    ) -> Result<MorphConfig, AlpacaError> {
+        let missing = |table| self.error(start, &format!("mode is {} but no [{}] section is given", table, table));
+
+        let morph = match mode.get_ref().as_str() {
+            "probabilistic" => {
+                let p = probabilistic.as_ref().ok_or_else(|| missing("probabilistic"))?;
+                let raw = p.get_ref();
+                let config = MorphConfig::Probabilistic(ProbabilisticConfig {
+                    dist_html_size: self.dist(&raw.dist_html_size)?,
+                    dist_obj_num: self.dist(&raw.dist_obj_num)?,
+                    dist_obj_size: self.dist(&raw.dist_obj_size)?,
+                    use_total_obj_size: raw.use_total_obj_size,
+                });
+                config.validate().map_err(|e| self.error(p.span().start, &config_message(e)))?;
+                config
+            },
+            "deterministic" => {
+                let d = deterministic.as_ref().ok_or_else(|| missing("deterministic"))?;
+                let raw = d.get_ref();
+                let config = MorphConfig::Deterministic(DeterministicConfig {
+                    obj_num: raw.obj_num,
+                    obj_size: raw.obj_size,
+                    max_obj_size: raw.max_obj_size,
+                });
+                config.validate().map_err(|e| self.error(d.span().start, &config_message(e)))?;
+                config
+            },
+            other => return Err(self.error(mode.span().start, &format!("unknown mode \"{}\", expected \"probabilistic\" or \"deterministic\"", other))),
+        };
+        Ok(morph)
+    }
+
+    fn dist(&self, dist: &Spanned<String>) -> Result<Dist, AlpacaError> {
+        let mut spec = dist.get_ref().clone();
+        if spec.ends_with(".dist") && Path::new(&spec).is_relative() {
+            spec = self.dir.join(&spec).to_string_lossy().into_owned();
+        }
+        Dist::from(&spec).map_err(|e| self.error(dist.span().start, &e.to_string()))
+    }
+
+    // An error at the line containing the byte `offset` of the file.
+    fn error(&self, offset: usize, msg: &str) -> AlpacaError {
+        let offset = offset.min(self.data.len());
+        let line = self.data.as_bytes()[..offset].iter().filter(|&&b| b == b'\n').count() + 1;
+        AlpacaError::Config(format!("{}:{}: {}", self.name, line, msg.trim_end()))
+    }
+}
+
+// The message of an error, without the "invalid configuration" prefix when
+// it is going to be wrapped in another Config error.
+fn config_message(e: AlpacaError) -> String {
+    match e {
+        AlpacaError::Config(msg) => msg,
+        e => e.to_string(),
+    }
+}
@@ -0,0 +1,138 @@
+//! Finds and rewrites the object references (`url()` and `@import`) of CSS.
+use std::ops::Range;
+
+/// Length of "?alpaca-padding=", added to each rewritten reference together
+/// with the digits of the target size.
+pub const REF_PARAM_SIZE: usize = 16;
+
+/// An object reference in a stylesheet.
+pub struct CssRef {
+    /// Byte range of the uri in the stylesheet (without quotes)
+    pub range: Range<usize>,
+    /// The uri, as written in the stylesheet
+    pub uri: String,
+    /// Whether the reference is an `@import` (of another stylesheet)
+    pub import: bool,
+}
+
+/// Finds the uris of `url(...)` and `@import "..."` in `css`, in order.
+/// Comments are skipped, as well as uris that cannot refer to an object
+/// (`data:`, fragments) or that cannot be rewritten (containing escapes).
+pub fn scan_refs(css: &str) -> Vec<CssRef> {
+    let bytes = css.as_bytes();
+    let mut refs = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = find(bytes, i + 2, b"*/").map_or(bytes.len(), |end| end + 2);
+            },
+            b'"' | b'\'' => {
+                i = string_end(bytes, i);
+            },
+            b'@' if starts_with_ignore_case(&bytes[i + 1..], b"import") => {
+                i = skip_whitespace(bytes, i + 7);
+                if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
+                    i = push_quoted_ref(css, i, true, &mut refs);
+                } else if starts_with_ignore_case(&bytes[i..], b"url(") {
+                    i = scan_url(css, i, true, &mut refs);
+                }
+            },
+            b'u' | b'U' if starts_with_ignore_case(&bytes[i..], b"url(") && (i == 0 || !is_ident(bytes[i - 1])) => {
+                i = scan_url(css, i, false, &mut refs);
+            },
+            _ => i += 1,
+        }
+    }
+    refs
+}
+
+/// Replaces the uri of each reference with the given one. `refs` must be
+/// sorted and non-overlapping, as returned by `scan_refs`.
+pub fn rewrite(css: &str, refs: &[(&CssRef, String)]) -> String {
+    let mut out = String::with_capacity(css.len() + refs.len() * 32);
+    let mut pos = 0;
+    for &(css_ref, ref uri) in refs {
+        out.push_str(&css[pos..css_ref.range.start]);
+        out.push_str(uri);
+        pos = css_ref.range.end;
+    }
+    out.push_str(&css[pos..]);
+    out
+}
+
+/// The maximum number of bytes that rewriting `refs` references adds, for
+/// target sizes up to `max_target_size`.
+pub fn rewrite_overhead(refs: usize, max_target_size: usize) -> usize {
+    refs * (REF_PARAM_SIZE + max_target_size.to_string().len())
+}
+
+// Pushes the uri of the url( starting at `start`, returns its end.
+fn scan_url(css: &str, start: usize, import: bool, refs: &mut Vec<CssRef>) -> usize {
+    let bytes = css.as_bytes();
+    let mut i = skip_whitespace(bytes, start + 4);
+    if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
+        return push_quoted_ref(css, i, import, refs);
+    }
+    let uri_start = i;
+    while i < bytes.len() && bytes[i] != b')' && !bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    push_ref(css, uri_start, i, import, refs);
+    i
+}
+
+// Pushes the uri of the string starting at `start`, returns its end.
+fn push_quoted_ref(css: &str, start: usize, import: bool, refs: &mut Vec<CssRef>) -> usize {
+    let bytes = css.as_bytes();
+    let end = string_end(bytes, start);
+    let terminated = end > start + 1 && bytes[end - 1] == bytes[start];
+    push_ref(css, start + 1, if terminated { end - 1 } else { end }, import, refs);
+    end
+}
+
+fn push_ref(css: &str, start: usize, end: usize, import: bool, refs: &mut Vec<CssRef>) {
+    let uri = match css.get(start..end) {
+        Some(uri) => uri,
+        None => return,
+    };
+    if uri.is_empty() || uri.starts_with('#') || uri.contains('\\') || uri.get(..5).is_some_and(|s| s.eq_ignore_ascii_case("data:")) {
+        return;
+    }
+    refs.push(CssRef { range: start..end, uri: String::from(uri), import });
+}
+
+// Position after the string starting with the quote at `start`.
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i = (i + 2).min(bytes.len()),
+            b'\n' => return i,      // unterminated string
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    bytes.get(from..)?.windows(needle.len()).position(|w| w == needle).map(|pos| from + pos)
+}
+
+fn skip_whitespace(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && bytes[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+fn is_ident(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+}
@@ -1,6 +1,7 @@
 //! Provides functions to sample objects' count and size
-//!	using the ALPaCA's deterministic way.
+//! using the ALPaCA's deterministic way.
 use rand_distr::Distribution;
+use error::AlpacaError;
 
 /// Returns the next multiple of "num" which is greater
 /// or equal than "min".
@@ -21,9 +22,9 @@ pub fn get_multiples_in_range(
     obj_size: usize,
     max_obj_size: usize,
     n: usize,
-) -> Result<Vec<usize>, String> {
-    if (obj_size > max_obj_size) || (max_obj_size % obj_size != 0) {
-        return Err(format!("max_obj_size ({}) must be greater-or-equal and a multiple of obj_size ({})", max_obj_size, obj_size));
+) -> Result<Vec<usize>, AlpacaError> {
+    if (obj_size > max_obj_size) || !max_obj_size.is_multiple_of(obj_size) {
+        return Err(AlpacaError::Config(format!("max_obj_size ({}) must be greater-or-equal and a multiple of obj_size ({})", max_obj_size, obj_size)));
     }
 
     let mut sizes: Vec<usize> = Vec::with_capacity(n); // Vector of target sizes.
@@ -4,7 +4,7 @@ use std::{str, fs};
 use rand::Rng;
 use rand_distr;
 use rand_distr::Distribution;
-use aux::*;
+use error::AlpacaError;
 
 // Number of tries per sample. If no sampled number satisfies a specified
 // threshold after `SAMPLE_LIMIT` tries the sampling function returns Err.
@@ -20,51 +20,55 @@ pub struct Dist {
 /// Parses a given distribution from the config file
 impl Dist {
     /// Construct a Distributions object.
-    pub fn from(dist: &str) -> Result<Dist,String> {
+    pub fn from(dist: &str) -> Result<Dist,AlpacaError> {
         if dist.ends_with(".dist") {
             // A distribution file has been given
 
-            let res = stringify_error(fs::read_to_string(dist.clone()));
-            if res.is_err() {
-                eprint!("libalpaca: cannot open {}: \n", dist);
-            }
-            let data = res?;
+            let data = fs::read_to_string(dist).map_err(|e| AlpacaError::Io(String::from(dist), e))?;
 
             // Construct the 2 vectors containing the values and probabilities
             let mut values: Vec<Vec<usize>> = Vec::new();
             let mut probs: Vec<f64> = Vec::new();
-            for line in data.lines() {
-                let l = String::from(line);
-                let v:Vec<&str> = l.split_whitespace().collect();
-                if values.len() > 0 && v.len() != values[0].len()+1 {
-                    return Err(format!("invalid dist file {}, line {}", dist, line));
+            for (i, line) in data.lines().enumerate() {
+                let v:Vec<&str> = line.split_whitespace().collect();
+                if v.is_empty() {
+                    continue;
+                }
+                let invalid = || AlpacaError::Distribution(format!("invalid dist file {}, line {}: {}", dist, i+1, line));
+
+                if v.len() < 2 || (!values.is_empty() && v.len() != values[0].len()+1) {
+                    return Err(invalid());
                 }
-                probs.push(v[0].parse().unwrap());
-                values.push(v[1..].iter().map(|e| e.parse().unwrap()).collect());
+                probs.push(v[0].parse().map_err(|_| invalid())?);
+                values.push(v[1..].iter().map(|e| e.parse()).collect::<Result<_,_>>().map_err(|_| invalid())?);
+            }
+            if values.is_empty() {
+                return Err(AlpacaError::Distribution(format!("empty dist file {}", dist)));
             }
 
-            return Ok(Dist {
+            Ok(Dist {
                 name: String::from("custom"),
                 params: probs,
                 values: Some(values),
-            });
+            })
 
-        } else if dist == "" || dist == "Joint" {
-            return Ok( Dist {
+        } else if dist.is_empty() || dist == "Joint" {
+            Ok( Dist {
                 name: String::from(dist),
                 params: Vec::new(),
                 values: None,
-            });
+            })
 
         } else {
 
-            let tokens: Vec<&str> = dist.split("/").collect();
+            let tokens: Vec<&str> = dist.split('/').collect();
             if tokens.len() != 2 {
-                return Err(format!("invalid distribution {}", dist));
+                return Err(AlpacaError::Distribution(format!("invalid distribution {}", dist)));
             }
 
             let name = tokens[0];
-            let params: Vec<f64> = tokens[1].split(",").map(|s| s.parse().unwrap()).collect(); // Distributions parameters
+            let params: Vec<f64> = tokens[1].split(',').map(|s| s.trim().parse()).collect::<Result<_,_>>()
+                .map_err(|_| AlpacaError::Distribution(format!("invalid parameters in distribution {}", dist)))?; // Distributions parameters
 
             let params_needed = match name {
                 "Normal" => 2,
@@ -73,24 +77,28 @@ impl Dist {
                 "Poisson" => 1,
                 "Binomial" => 2,
                 "Gamma" => 2,
-                _ => return Err(format!("invalid distribution {}", dist)),
+                _ => return Err(AlpacaError::Distribution(format!("invalid distribution {}", dist))),
             };
 
             // A predefined distribution and its parameters have been given.
             if params.len() != params_needed {
-                return Err(format!("{} distribution requires {} params, {} given", name, params_needed, params.len()));
+                return Err(AlpacaError::Distribution(format!("{} distribution requires {} params, {} given", name, params_needed, params.len())));
             }
 
-            return Ok(Dist {
+            let dist = Dist {
                 name: String::from(name),
-                params: params,
+                params,
                 values: None,
-            });
+            };
+
+            // invalid parameters (eg negative std_dev) are only detected when sampling
+            sample_predefined(&dist)?;
+            Ok(dist)
         }
     }
 }
 
-pub fn sample_ge_many(dist:&Dist, lower_bound:usize, samples:usize) -> Result<Vec<usize>,String> {
+pub fn sample_ge_many(dist:&Dist, lower_bound:usize, samples:usize) -> Result<Vec<usize>,AlpacaError> {
     let mut vec: Vec<usize> = Vec::new();
     for _ in 0..samples {
         vec.push(sample_ge(dist, lower_bound)?);
@@ -99,17 +107,17 @@ pub fn sample_ge_many(dist:&Dist, lower_bound:usize, samples:usize) -> Result<Ve
 }
 
 /// Samples a value greater or equal than the given one
-pub fn sample_ge(dist:&Dist, lower_bound:usize) -> Result<usize,String> {
+pub fn sample_ge(dist:&Dist, lower_bound:usize) -> Result<usize,AlpacaError> {
     if dist.name == "custom" {
         let values = dist.values.as_ref().unwrap();
         if values[0].len() != 1 {
-            return Err(format!("alpaca: custom distribution contains {} values per row, expected 1", values[0].len()));
+            return Err(AlpacaError::Distribution(format!("custom distribution contains {} values per row, expected 1", values[0].len())));
         }
 
         // sample from custom distribution in a single try, by considering only values >= lower_bound
         let total_mass:f64 = (0..values.len()).filter(|i| values[*i][0] >= lower_bound).map(|i| dist.params[i]).sum();
         if total_mass < 1e-5 {
-            return Err(format!("values >= {} have prob 0 in custom distribution", lower_bound));
+            return Err(AlpacaError::Sampling(format!("values >= {} have prob 0 in custom distribution", lower_bound)));
         }
 
         let probability: f64 = rand::thread_rng().sample(rand_distr::OpenClosed01);
@@ -117,10 +125,10 @@ pub fn sample_ge(dist:&Dist, lower_bound:usize) -> Result<usize,String> {
         let mut sampled_num = 0;
 
         // Sample a value from the given distribution
-        for i in 0..values.len() {
-            if values[i][0] >= lower_bound {
-                sampled_num = values[i][0];            // make sure we keep one
-                sum += dist.params[i] / total_mass;
+        for (value, prob) in values.iter().zip(&dist.params) {
+            if value[0] >= lower_bound {
+                sampled_num = value[0];            // make sure we keep one
+                sum += prob / total_mass;
                 if sum >= probability {
                     break;
                 }
@@ -128,32 +136,32 @@ pub fn sample_ge(dist:&Dist, lower_bound:usize) -> Result<usize,String> {
         }
         Ok(sampled_num)
 
-    } else if dist.name == "" {
+    } else if dist.name.is_empty() {
         // empty dist means use the real value
         Ok(lower_bound)
 
     } else {
         for _ in 0..SAMPLE_LIMIT {
-            let sampled_num = sample_predefined(dist);
+            let sampled_num = sample_predefined(dist)?;
             if sampled_num >= lower_bound {
                 return Ok(sampled_num);
             }
         }
-        Err(format!("SAMPLE_LIMIT={} reached for distribution {}", SAMPLE_LIMIT, dist.name))
+        Err(AlpacaError::Sampling(format!("SAMPLE_LIMIT={} reached for distribution {}", SAMPLE_LIMIT, dist.name)))
     }
 }
 
 // returns a pair (a,b) from a joint distribution, satisfying
 //    a >= lb_a   and   b >= lb_b      where (a,b) = lower_bound
 //
-pub fn sample_pair_ge(dist:&Dist, lower_bound: (usize, usize)) -> Result<(usize,usize),String> {
+pub fn sample_pair_ge(dist:&Dist, lower_bound: (usize, usize)) -> Result<(usize,usize),AlpacaError> {
     if dist.name != "custom" {
-        return Err(format!("alpaca: joint distributions need to be given in a file (got: {})", dist.name));
+        return Err(AlpacaError::Distribution(format!("joint distributions need to be given in a file (got: {})", dist.name)));
     }
 
     let values = dist.values.as_ref().unwrap();
     if values[0].len() != 2 {
-        return Err(format!("alpaca: custom distribution contains {} values per row, expected 2", values[0].len()));
+        return Err(AlpacaError::Distribution(format!("custom distribution contains {} values per row, expected 2", values[0].len())));
     }
 
     // sample from custom distribution in a single try, by considering only values >= lower_bound
@@ -161,7 +169,7 @@ pub fn sample_pair_ge(dist:&Dist, lower_bound: (usize, usize)) -> Result<(usize,
 
     let total_mass:f64 = (0..values.len()).filter(|i| values[*i][0] >= lb_a && values[*i][1] >= lb_b).map(|i| dist.params[i]).sum();
     if total_mass < 1e-5 {
-        return Err(format!("values >= ({},{}) have prob 0 in custom distribution", lb_a, lb_b));
+        return Err(AlpacaError::Sampling(format!("values >= ({},{}) have prob 0 in custom distribution", lb_a, lb_b)));
     }
 
     let probability: f64 = rand::thread_rng().sample(rand_distr::OpenClosed01);
@@ -170,11 +178,11 @@ pub fn sample_pair_ge(dist:&Dist, lower_bound: (usize, usize)) -> Result<(usize,
     let mut sampled_b = 0;
 
     // Sample a value from the given distribution
-    for i in 0..values.len() {
-        if values[i][0] >= lb_a && values[i][1] >= lb_b {
-            sampled_a = values[i][0];            // make sure
-            sampled_b = values[i][1];            // we keep one
-            sum += dist.params[i] / total_mass;
+    for (value, prob) in values.iter().zip(&dist.params) {
+        if value[0] >= lb_a && value[1] >= lb_b {
+            sampled_a = value[0];            // make sure
+            sampled_b = value[1];            // we keep one
+            sum += prob / total_mass;
             if sum >= probability {
                 break;
             }
@@ -184,36 +192,44 @@ pub fn sample_pair_ge(dist:&Dist, lower_bound: (usize, usize)) -> Result<(usize,
 }
 
 fn f64_to_usize(f:f64) -> usize {
-    return if f < 0.0 { 0 } else { f as usize };
+    if f < 0.0 { 0 } else { f as usize }
 }
 
-fn sample_predefined(dist:&Dist) -> usize {
-   match dist.name.as_str() {
+fn sample_predefined(dist:&Dist) -> Result<usize,AlpacaError> {
+    // the constructors only fail on invalid parameters (eg negative std_dev)
+    let invalid = |e: rand_distr::NormalError| AlpacaError::Distribution(format!("invalid {} parameters: {:?}", dist.name, e));
+    let mut rng = rand::thread_rng();
+
+    Ok(match dist.name.as_str() {
         "Normal" => {
-            let d = rand_distr::Normal::new(dist.params[0], dist.params[1]).unwrap();
-            f64_to_usize(d.sample(&mut rand::thread_rng()))
+            let d = rand_distr::Normal::new(dist.params[0], dist.params[1]).map_err(invalid)?;
+            f64_to_usize(d.sample(&mut rng))
         },
         "LogNormal" => {
-            let d = rand_distr::LogNormal::new(dist.params[0], dist.params[1]).unwrap();
-            f64_to_usize(d.sample(&mut rand::thread_rng()))
+            let d = rand_distr::LogNormal::new(dist.params[0], dist.params[1]).map_err(invalid)?;
+            f64_to_usize(d.sample(&mut rng))
         },
         "Exp" => {
-            let d = rand_distr::Exp::new(dist.params[0]).unwrap();
-            f64_to_usize(d.sample(&mut rand::thread_rng()))
+            let d = rand_distr::Exp::new(dist.params[0])
+                .map_err(|e| AlpacaError::Distribution(format!("invalid Exp parameters: {:?}", e)))?;
+            f64_to_usize(d.sample(&mut rng))
+        },
+        "Poisson" => {
+            let d = rand_distr::Poisson::new(dist.params[0])
+                .map_err(|e| AlpacaError::Distribution(format!("invalid Poisson parameters: {:?}", e)))?;
+            let sample: u64 = d.sample(&mut rng);
+            sample as usize
         },
-        // "Poisson" => {
-        //     let d = Poisson::new(dist.params[0]).unwrap();
-        //     return Ok(d.sample(&mut rand::thread_rng()) as usize);
-        // },
         "Binomial" => {
-            let d = rand_distr::Binomial::new(dist.params[0] as u64, dist.params[1]).unwrap();
-            d.sample(&mut rand::thread_rng()) as usize      // sample is u64
+            let d = rand_distr::Binomial::new(dist.params[0] as u64, dist.params[1])
+                .map_err(|e| AlpacaError::Distribution(format!("invalid Binomial parameters: {:?}", e)))?;
+            d.sample(&mut rng) as usize      // sample is u64
         },
         "Gamma" => {
-            let d = rand_distr::Gamma::new(dist.params[0], dist.params[1]).unwrap();
-            f64_to_usize(d.sample(&mut rand::thread_rng()) )
+            let d = rand_distr::Gamma::new(dist.params[0], dist.params[1])
+                .map_err(|e| AlpacaError::Distribution(format!("invalid Gamma parameters: {:?}", e)))?;
+            f64_to_usize(d.sample(&mut rng))
         },
-        _ => panic!("not possible"),
-    }
+        _ => return Err(AlpacaError::Distribution(format!("cannot sample from distribution {}", dist.name))),
+    })
 }
-
@@ -1,45 +1,86 @@
 //! Contains parsing routines
-use std::{str,fs,path::Path};
+use std::{str,ops::Range,path::{Path,PathBuf}};
 use kuchiki::traits::*;
 use kuchiki::{parse_html_with_options, NodeRef, ParseOpts};
 use html5ever::{interface::QualName,LocalName,ns,namespace_url,serialize,serialize::{SerializeOpts}};
-use aux;
+use error::AlpacaError;
+use morphing::RequestContext;
+use resolver::{ObjectResolver, ResolvedObject};
+use css;
+use uri;
+use url::Url;
 
 /// Defines our basic object types, each of which has a corresponding
 /// unique (distribution, padding type) tuple.
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum ObjectKind {
     FakeIMG,	/// Fake alpaca image
     HTML,
     CSS,
     IMG,		/// IMG: PNG, JPEG, etc.
 	JS,
+    SVG,
+    Font,		// WOFF, WOFF2, TrueType, OpenType
+    JSON,
+    Wasm,
+    PDF,
     Unknown,
 }
 
+/// Where the uri of an object is located in its node.
+pub enum Location {
+    /// The whole value of an attribute
+    Attr(&'static str),
+    /// A part of an attribute value (eg a url() in a style attribute)
+    AttrRange(&'static str, Range<usize>),
+    /// A part of a text node (eg a url() in a <style> block)
+    TextRange(Range<usize>),
+}
+
+impl Location {
+    /// Start of the uri in its attribute or text. Uris are rewritten from
+    /// last to first, so that the ranges of the rest remain valid.
+    pub fn start(&self) -> usize {
+        match *self {
+            Location::Attr(_) => 0,
+            Location::AttrRange(_, ref range) | Location::TextRange(ref range) => range.start,
+        }
+    }
+}
+
 /// An object to be used in the morphing process.
 pub struct Object {
     /// Type of the Object
     pub kind: ObjectKind,
-    /// Content (Vector of bytes) of the Object
-    pub content: Vec<u8>,
+    /// Size of the Object
+    pub size: usize,
+    /// File containing the Object, whose extension tells its format
+    pub path: Option<PathBuf>,
+    /// Number of object references, if it is a stylesheet
+    pub css_refs: usize,
     /// Node in the html
     pub node: Option<NodeRef>,
     /// Size to pad the Object to
     pub target_size: Option<usize>,
     /// The uri of the object, as mentioned in the html source
     pub uri: String,
+    /// The location of the uri in `node`
+    pub location: Location,
 }
 
 impl Object {
-    /// Construct a real object from the html page
-    pub fn existing(content: &[u8], kind: ObjectKind, uri: String, node: &NodeRef) -> Object {
+    /// Construct a real object from the html page, as resolved. `kind` is
+    /// the one implied by the html element, used if the resolver found none.
+    pub fn existing(res: ResolvedObject, kind: ObjectKind, uri: String, node: &NodeRef, location: Location) -> Object {
         Object {
-            kind: kind,
-            content: content.to_vec(),
+            kind: res.kind.unwrap_or(kind),
+            size: res.size,
+            path: res.path,
+            css_refs: res.css_refs,
             node: Some(node.clone()),
             target_size: None,
-            uri: uri,
+            uri,
+            location,
         }
     }
 
@@ -47,10 +88,13 @@ impl Object {
     pub fn fake_image(target_size: usize) -> Object {
         Object {
             kind: ObjectKind::FakeIMG,
-            content: Vec::new(),
+            size: 0,
+            path: None,
+            css_refs: 0,
             node: None,
             target_size: Some(target_size),
             uri: String::from("pad_object"),
+            location: Location::Attr("src"),
         }
     }
 }
@@ -58,16 +102,43 @@ impl Object {
 
 
 
-/// Parses the object's kind from its raw representation
+/// Parses the object's kind from its raw representation (a Content-Type,
+/// whose parameters are ignored)
 pub fn parse_object_kind(mime: &str) -> ObjectKind {
-	match mime {
+	let mime = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+	match mime.as_str() {
 		"text/html" => ObjectKind::HTML,
 		"text/css" => ObjectKind::CSS,
+		"application/javascript" | "text/javascript" | "application/x-javascript"
+			| "application/ecmascript" | "text/ecmascript" => ObjectKind::JS,
+		"image/svg+xml" => ObjectKind::SVG,
 		x if x.starts_with("image/") => ObjectKind::IMG,
+		x if x.starts_with("font/") => ObjectKind::Font,
+		"application/font-woff" | "application/font-sfnt" | "application/x-font-ttf"
+			| "application/x-font-otf" | "application/vnd.ms-opentype" => ObjectKind::Font,
+		x if x == "application/json" || x.ends_with("+json") => ObjectKind::JSON,
+		"application/wasm" => ObjectKind::Wasm,
+		"application/pdf" => ObjectKind::PDF,
     	_=> ObjectKind::Unknown
     }
 }
 
+/// The kind of an object from the extension of its file, None if unknown.
+pub fn path_object_kind(path: &Path) -> Option<ObjectKind> {
+	let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+	Some(match ext.as_str() {
+		"css" => ObjectKind::CSS,
+		"js" | "mjs" => ObjectKind::JS,
+		"svg" => ObjectKind::SVG,
+		"png" | "jpg" | "jpeg" | "gif" | "webp" | "avif" | "bmp" | "ico" => ObjectKind::IMG,
+		"woff" | "woff2" | "ttf" | "otf" => ObjectKind::Font,
+		"json" => ObjectKind::JSON,
+		"wasm" => ObjectKind::Wasm,
+		"pdf" => ObjectKind::PDF,
+		_ => return None,
+	})
+}
+
 /// Parses the target size of an object from its HTTP request query.
 /// Returns 0 on error.
 pub fn parse_target_size(query: &str) -> usize {
@@ -76,67 +147,179 @@ pub fn parse_target_size(query: &str) -> usize {
 	let size_str = split2[0];
 
 	//Return the size
-	match size_str.parse::<usize>() {
-	  Ok(size) => return size,
-	  Err(_) => return 0
-	}
+	size_str.parse::<usize>().unwrap_or(0)
 }
 
-/// Parses the objects contained in an HTML page.
+/// Parses the objects contained in an HTML page, finding their size with
+/// `resolver`. Objects that cannot be resolved are left unchanged, the
+/// errors are added to `warnings`.
 //
-pub fn parse_objects(document: &NodeRef, root: &str, uri: &str, alias: usize) -> Vec<Object> {
+pub fn parse_objects(document: &NodeRef, ctx: &RequestContext, resolver: &dyn ObjectResolver, warnings: &mut Vec<AlpacaError>) -> Vec<Object> {
 	//Objects vector
 	let mut objects: Vec<Object> = Vec::with_capacity(10);
 	let mut found_favicon = false;
 
+	// relative references are resolved against <base href>, if present
+	let base = document_base(document, ctx);
+	let mut resolve_object = |uri: String, kind: ObjectKind, node: &NodeRef, location: Location| {
+		match resolve_ref(&uri, &base, ctx, resolver) {
+			Ok(res) => res.map(|res| Object::existing(res, kind, uri, node, location)),
+			Err(e) => { warnings.push(e); None },
+		}
+	};
+
 	// Find:
-	// - <img> and <link href="favicon.ico" rel="shortcut icon">
+	// - <img src/srcset> and <link href="favicon.ico" rel="shortcut icon">
 	// - <link rel="stylesheet">
 	// - <script src="...">
-    for node_data in document.select("img,link,script").unwrap() {
+	// - <picture><source srcset>, <video poster>, <audio>/<video> src and <source src>
+	// - <track src>, <embed src>, <object data>, <input type="image" src>
+    for node_data in document.select("img,link,script,source,video,audio,track,embed,object,input").unwrap() {
 		let node = node_data.as_node();
 		let name = node_data.name.local.to_lowercase();
-
-		let path_attr = if name == "link" { "href" } else { "src" };
-		let path = match node_get_attribute(node, path_attr) {
-			Some(p) if p != "" && !p.starts_with("data:") => p,
-			_ => continue,
-		};
-
 		let rel = node_get_attribute(node, "rel").unwrap_or_default();
-		let kind = match (name.as_str(), rel.as_str()) {
-			("link", "stylesheet") => ObjectKind::CSS,
-			("link", "shortcut icon") | ("link", "icon") => { found_favicon = true; ObjectKind::IMG },
-			("script", _) => ObjectKind::JS,
-			("img", _) => ObjectKind::IMG,
+		let parent = node.parent()
+			.and_then(|p| p.as_element().map(|elem| elem.name.local.to_lowercase()))
+			.unwrap_or_default();
+
+		// the attributes containing uris, with the kind of the objects they refer to
+		let attrs: &[(&'static str, ObjectKind)] = match (name.as_str(), rel.as_str()) {
+			("link", "stylesheet") => &[("href", ObjectKind::CSS)],
+			("link", "shortcut icon") | ("link", "icon") => &[("href", ObjectKind::IMG)],
+			("script", _) => &[("src", ObjectKind::JS)],
+			("img", _) => &[("src", ObjectKind::IMG), ("srcset", ObjectKind::IMG)],
+			("source", _) if parent == "picture" => &[("srcset", ObjectKind::IMG)],
+			("source", _) | ("audio", _) | ("track", _) | ("embed", _) => &[("src", ObjectKind::Unknown)],
+			("video", _) => &[("src", ObjectKind::Unknown), ("poster", ObjectKind::IMG)],
+			("object", _) => &[("data", ObjectKind::Unknown)],
+			("input", _) if node_get_attribute(node, "type").is_some_and(|t| t.eq_ignore_ascii_case("image")) => &[("src", ObjectKind::IMG)],
 			_ => continue,
 		};
 
-		/* Consider the posibility that the css file already has some GET parameters */
-		let split: Vec<&str> = path.split('?').collect();
-		let relative = split[0];
-		
-		let fullpath;
-		match uri_to_abs_fs_path(root, relative, uri, alias) {
-			Some(absolute) => fullpath = absolute,
-			None => continue
+		for &(attr, kind) in attrs {
+			let value = match node_get_attribute(node, attr) {
+				Some(value) => value,
+				None => continue,
+			};
+			if attr == "srcset" {
+				// each candidate of the list is a separate object
+				for range in srcset_urls(&value) {
+					let uri = &value[range.clone()];
+					if is_object_uri(uri) {
+						objects.extend(resolve_object(String::from(uri), kind, node, Location::AttrRange(attr, range)));
+					}
+				}
+			} else if is_object_uri(&value) {
+				found_favicon |= name == "link" && kind == ObjectKind::IMG;
+				objects.extend(resolve_object(value, kind, node, Location::Attr(attr)));
+			}
 		}
+    }
 
-		match aux::stringify_error(fs::read(&fullpath)) {
-			Ok(data) => objects.push(Object::existing(&data, kind, path, node)),
-			Err(e) => { eprint!("libalpaca: cannot read {} ({})\n", fullpath, e); continue },
+	// url() and @import references in <style> blocks and style attributes
+	for node_data in document.select("style").unwrap() {
+		for child in node_data.as_node().children() {
+			let text = match child.as_text() {
+				Some(text) => text.borrow().clone(),
+				None => continue,
+			};
+			for css_ref in css::scan_refs(&text) {
+				let kind = if css_ref.import { ObjectKind::CSS } else { ObjectKind::Unknown };
+				objects.extend(resolve_object(css_ref.uri, kind, &child, Location::TextRange(css_ref.range)));
+			}
 		}
-    }
+	}
+	for node_data in document.select("[style]").unwrap() {
+		let node = node_data.as_node();
+		let style = node_get_attribute(node, "style").unwrap_or_default();
+		for css_ref in css::scan_refs(&style) {
+			objects.extend(resolve_object(css_ref.uri, ObjectKind::Unknown, node, Location::AttrRange("style", css_ref.range)));
+		}
+	}
 
 	// If no favicon was found, insert an empty one
 	if !found_favicon {
 		insert_empty_favicon(document);
 	}
 
-    objects.sort_unstable_by(|a, b| b.content.len().cmp(&a.content.len()));		// larger first
+    objects.sort_unstable_by_key(|obj| std::cmp::Reverse(obj.size));		// larger first
 	objects
 }
 
+/// The url that relative references of the document are resolved against:
+/// its first `<base href>` (itself relative to the page), or the page url.
+pub fn document_base(document: &NodeRef, ctx: &RequestContext) -> Url {
+	let page = uri::page_url(ctx.http_host, ctx.uri);
+	let href = document.select("base[href]").unwrap().next()
+		.and_then(|node_data| node_get_attribute(node_data.as_node(), "href"));
+
+	match href {
+		Some(href) => page.join(&href).unwrap_or(page),
+		None => page,
+	}
+}
+
+// Uris that may refer to an object of the server.
+fn is_object_uri(uri: &str) -> bool {
+	!uri.is_empty() && !uri.starts_with("data:")
+}
+
+/// Returns the byte ranges of the urls in a `srcset` attribute, a comma
+/// separated list of urls each followed by optional descriptors.
+pub fn srcset_urls(srcset: &str) -> Vec<Range<usize>> {
+	let bytes = srcset.as_bytes();
+	let mut urls = Vec::new();
+	let mut i = 0;
+
+	loop {
+		while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b',') {
+			i += 1;
+		}
+		if i >= bytes.len() {
+			break;
+		}
+
+		// the url is a run of non-whitespace, trailing commas end the candidate
+		let start = i;
+		while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+			i += 1;
+		}
+		let mut end = i;
+		while end > start && bytes[end - 1] == b',' {
+			end -= 1;
+		}
+		if end > start {
+			urls.push(start..end);
+		}
+
+		// skip the descriptors, up to the next comma outside parentheses
+		if end == i {
+			let mut depth = 0usize;
+			while i < bytes.len() {
+				match bytes[i] {
+					b'(' => depth += 1,
+					b')' => depth = depth.saturating_sub(1),
+					b',' if depth == 0 => break,
+					_ => {},
+				}
+				i += 1;
+			}
+		}
+	}
+	urls
+}
+
+/// Resolves the object referenced by `uri` in the page (or stylesheet)
+/// `ctx.uri`, relative to `base`. Returns None if it should be skipped
+/// (eg it is external).
+pub fn resolve_ref(uri: &str, base: &Url, ctx: &RequestContext, resolver: &dyn ObjectResolver) -> Result<Option<ResolvedObject>, AlpacaError> {
+	let page = uri::page_url(ctx.http_host, ctx.uri);
+	match uri::object_path(base, &page, uri) {
+		Some(path) => resolver.resolve(ctx, &path),
+		None => Ok(None),
+	}
+}
+
 pub fn insert_empty_favicon(document: &NodeRef) {
     // append the <link> either to the <head> tag, if exists, otherwise
     // to the whole document
@@ -153,16 +336,20 @@ pub fn insert_empty_favicon(document: &NodeRef) {
 }
 
 /// Maps a (relative or absolute) uri, to an absolute filesystem path.
-/// Returns None if uri_path is located in another server
-fn uri_to_abs_fs_path(root: &str, relative: &str, page_uri: &str, alias: usize) -> Option<String> {
-	if relative.starts_with("https://") || relative.starts_with("http://") {
+/// `.` and `..` are resolved lexically and never go above the root. The
+/// first `alias` bytes of `page_uri` are the location aliased to `root`,
+/// the uri must be in the same location. Returns None if uri_path is
+/// located in another server or location. The path may still escape the
+/// root through symlinks, see `resolver::confine`.
+pub fn uri_to_abs_fs_path(root: &str, relative: &str, page_uri: &str, alias: usize) -> Option<String> {
+	if relative.starts_with("https://") || relative.starts_with("http://") || relative.starts_with("//") {
 		return None;
 	}
 
 	let mut fs_relative = String::from(relative);
 
 	if !fs_relative.starts_with('/') {
-		let base = Path::new(page_uri).parent().unwrap().to_str().unwrap();
+		let base = Path::new(page_uri).parent().and_then(Path::to_str).unwrap_or("/");
 		
 		if !base.ends_with('/') {
 			fs_relative.insert(0,'/');
@@ -176,62 +363,61 @@ fn uri_to_abs_fs_path(root: &str, relative: &str, page_uri: &str, alias: usize)
 	let mut normalized: Vec<String> = Vec::with_capacity(components.len()); // Stack to be used for the normalization	
 
 	for comp in components {
-		if comp == "." || comp == "" {continue;}
+		if comp == "." || comp.is_empty() {continue;}
 		else if comp == ".." {
 			if !normalized.is_empty() {
 				normalized.pop();
 			}
 		}
+		else if comp.contains(['\\', '\0']) {
+			return None;	// not a separator on unix, but never a valid object either
+		}
 		else {
 			normalized.push("/".to_string()+comp);
 		}
 	}
 
-	let mut absolute: String = normalized.into_iter().collect(); // String with the resolved relative path
+	let absolute: String = normalized.into_iter().collect(); // String with the resolved relative path
 
-	if page_uri[..alias] != absolute[..alias] {
-		return None;
-	}
-
-	absolute = absolute[alias..].to_string(); // Remove alias characters in case there are any
-
-	absolute.insert_str(0,root); // Make the above path absolute by adding the root
+	// Remove the aliased location, if any (get fails on short or non-ASCII prefixes instead of panicking)
+	let location = page_uri.get(..alias)?;
+	let relative_to_root = absolute.strip_prefix(location)?;
 
-	Some(absolute)
+	// Make the above path absolute by adding the root
+	if root.ends_with('/') || relative_to_root.starts_with('/') || relative_to_root.is_empty() {
+		Some(format!("{}{}", root, relative_to_root))
+	} else {
+		Some(format!("{}/{}", root, relative_to_root))
+	}
 }
 
 pub fn parse_html(input: &str) -> NodeRef {
-    let mut opts = ParseOpts::default();
-    opts.tree_builder.drop_doctype = true;
-
-    let mut parser = parse_html_with_options(opts);
+    // the doctype is kept, browsers use quirks mode without it
+    let mut parser = parse_html_with_options(ParseOpts::default());
     parser.process(input.into());
     parser.finish()
 }
 
-pub fn serialize_html(dom: &NodeRef) -> Vec<u8> {
+pub fn serialize_html(dom: &NodeRef) -> Result<Vec<u8>, AlpacaError> {
     let mut buf: Vec<u8> = Vec::new();
 
     let opts = SerializeOpts::default();
 
     serialize(&mut buf, dom, opts)
-        .expect("serialization failed");
+        .map_err(|e| AlpacaError::Io(String::from("serialized html"), e))?;
 
-    buf
+    Ok(buf)
 }
 
 pub fn create_element(name: &str) -> NodeRef {
-    let qual_name = QualName::new(None, ns!(), LocalName::from(name));
+    let qual_name = QualName::new(None, ns!(html), LocalName::from(name));
     NodeRef::new_element(qual_name, Vec::new())
 }
 
 fn node_get_attribute(node: &NodeRef, name: &str) -> Option<String> {
     match node.as_element() {
         Some(element) => {
-            match element.attributes.borrow().get(name) {
-                Some(val) => Some(String::from(val)),
-                None => None,
-            }
+            element.attributes.borrow().get(name).map(String::from)
         },
         None => None,
     }
@@ -240,4 +426,26 @@ fn node_get_attribute(node: &NodeRef, name: &str) -> Option<String> {
 pub fn node_set_attribute(node: &NodeRef, name: &str, value: String) {
     let elem = node.as_element().unwrap();
     elem.attributes.borrow_mut().insert(name, value);
-}
\ No newline at end of file
+}
+
+/// Replaces the uri of an object at `location` of `node` with `uri`.
+pub fn node_set_uri(node: &NodeRef, location: &Location, uri: String) {
+    match *location {
+        Location::Attr(attr) => node_set_attribute(node, attr, uri),
+        Location::AttrRange(attr, ref range) => {
+            let mut value = node_get_attribute(node, attr).unwrap_or_default();
+            if value.get(range.clone()).is_some() {
+                value.replace_range(range.clone(), &uri);
+                node_set_attribute(node, attr, value);
+            }
+        },
+        Location::TextRange(ref range) => {
+            if let Some(text) = node.as_text() {
+                let mut text = text.borrow_mut();
+                if text.get(range.clone()).is_some() {
+                    text.replace_range(range.clone(), &uri);
+                }
+            }
+        },
+    }
+}
@@ -0,0 +1,98 @@
+//! Contains the error type returned by all fallible routines.
+use std::{error, fmt, io, str};
+
+// Codes returned by `AlpacaError::code`, also exported to C.
+pub const ALPACA_ERR_DISTRIBUTION: i32 = 1;
+pub const ALPACA_ERR_SAMPLING: i32 = 2;
+pub const ALPACA_ERR_CONFIG: i32 = 3;
+pub const ALPACA_ERR_IO: i32 = 4;
+pub const ALPACA_ERR_UTF8: i32 = 5;
+pub const ALPACA_ERR_PADDING_UNDERFLOW: i32 = 6;
+pub const ALPACA_ERR_TARGET_TOO_LARGE: i32 = 7;
+pub const ALPACA_ERR_INVALID_ARGUMENT: i32 = 8;
+pub const ALPACA_ERR_PANIC: i32 = 9;
+pub const ALPACA_ERR_ALLOC: i32 = 10;
+pub const ALPACA_ERR_ENCODING: i32 = 11;
+
+/// An error that occurred while morphing a page or padding an object.
+#[derive(Debug)]
+pub enum AlpacaError {
+    /// A distribution (or distribution file) could not be parsed
+    Distribution(String),
+    /// No value satisfying the requested bound could be sampled
+    Sampling(String),
+    /// The morphing parameters are inconsistent
+    Config(String),
+    /// I/O failed on a file (distribution or object) or on the html
+    Io(String, io::Error),
+    /// Content that was expected to be UTF-8 is not
+    Utf8(str::Utf8Error),
+    /// The content is already larger than the size it should be padded to
+    PaddingUnderflow { size: usize, target_size: usize },
+    /// The requested target size is larger than any sane configuration allows
+    TargetTooLarge(usize),
+    /// An argument passed through the C interface is invalid (eg NULL)
+    InvalidArgument(String),
+    /// A panic was caught at the C interface, with its message
+    Panic(String),
+    /// The caller-supplied allocator failed to provide the given size
+    Alloc(usize),
+    /// The page is in a character encoding that cannot be morphed (eg UTF-16)
+    Encoding(String),
+}
+
+impl AlpacaError {
+    /// A numeric code identifying the kind of the error, stable across
+    /// releases. 0 is reserved for "no error".
+    pub fn code(&self) -> i32 {
+        match *self {
+            AlpacaError::Distribution(_) => ALPACA_ERR_DISTRIBUTION,
+            AlpacaError::Sampling(_) => ALPACA_ERR_SAMPLING,
+            AlpacaError::Config(_) => ALPACA_ERR_CONFIG,
+            AlpacaError::Io(..) => ALPACA_ERR_IO,
+            AlpacaError::Utf8(_) => ALPACA_ERR_UTF8,
+            AlpacaError::PaddingUnderflow { .. } => ALPACA_ERR_PADDING_UNDERFLOW,
+            AlpacaError::TargetTooLarge(_) => ALPACA_ERR_TARGET_TOO_LARGE,
+            AlpacaError::InvalidArgument(_) => ALPACA_ERR_INVALID_ARGUMENT,
+            AlpacaError::Panic(_) => ALPACA_ERR_PANIC,
+            AlpacaError::Alloc(_) => ALPACA_ERR_ALLOC,
+            AlpacaError::Encoding(_) => ALPACA_ERR_ENCODING,
+        }
+    }
+}
+
+impl fmt::Display for AlpacaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AlpacaError::Distribution(ref msg) => write!(f, "invalid distribution: {}", msg),
+            AlpacaError::Sampling(ref msg) => write!(f, "sampling failed: {}", msg),
+            AlpacaError::Config(ref msg) => write!(f, "invalid configuration: {}", msg),
+            AlpacaError::Io(ref path, ref e) => write!(f, "I/O error on {}: {}", path, e),
+            AlpacaError::Utf8(ref e) => write!(f, "invalid UTF-8: {}", e),
+            AlpacaError::PaddingUnderflow { size, target_size } =>
+                write!(f, "target_size ({}) cannot match current size ({})", target_size, size),
+            AlpacaError::TargetTooLarge(target_size) =>
+                write!(f, "target_size ({}) more than the maximum, probably wrong configuration or an alpaca bug", target_size),
+            AlpacaError::InvalidArgument(ref msg) => write!(f, "invalid argument: {}", msg),
+            AlpacaError::Panic(ref msg) => write!(f, "panic: {}", msg),
+            AlpacaError::Alloc(size) => write!(f, "allocator failed to provide {} bytes", size),
+            AlpacaError::Encoding(ref name) => write!(f, "unsupported character encoding: {}", name),
+        }
+    }
+}
+
+impl error::Error for AlpacaError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            AlpacaError::Io(_, ref e) => Some(e),
+            AlpacaError::Utf8(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<str::Utf8Error> for AlpacaError {
+    fn from(e: str::Utf8Error) -> AlpacaError {
+        AlpacaError::Utf8(e)
+    }
+}
@@ -0,0 +1,934 @@
+//! C interface of the library, a thin layer over `morphing::Morpher`.
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::{fs, io, mem, ptr, slice, str};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use distribution::Dist;
+use error::AlpacaError;
+use config::{Config, HtmlPadding, MorphConfig, ProbabilisticConfig, DeterministicConfig};
+use morphing::{Morpher, RequestContext};
+use policy::{Pattern, Policy};
+use resolver::{ObjectResolver, ResolvedObject, DocumentRoot};
+use dom;
+use cache::ObjectCache;
+use pad::PadReader;
+use compress::Compression;
+use source::{PaddingSource, CsprngSource, AlphanumericSource, MimicSource, SeededSource};
+
+thread_local! {
+    // The last error that occurred in this thread, as (code, message)
+    static LAST_ERROR: RefCell<Option<(c_int, CString)>> = const { RefCell::new(None) };
+}
+
+/// Version of the C interface. Modules must check at startup that
+/// `alpaca_abi_version()` returns the version they were compiled against.
+pub const ALPACA_ABI_VERSION: u32 = 4;
+
+/// A buffer returned to C. `data` points to `len` bytes, the fields must not
+/// be modified by the caller. Release it with `alpaca_buffer_free`; a
+/// `capacity` of 0 means that the memory is not owned by the library (it
+/// is empty, or comes from the caller's allocator).
+#[repr(C)]
+pub struct AlpacaBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl AlpacaBuffer {
+    fn empty() -> AlpacaBuffer {
+        AlpacaBuffer { data: ptr::null_mut(), len: 0, capacity: 0 }
+    }
+}
+
+/// Allocation callback: returns `size` bytes of caller-owned memory, or NULL.
+/// Like the resolver callback, it may unwind (eg a panic of a callback written
+/// in Rust): the panic is caught and reported like those of the library.
+pub type AlpacaAllocFn = Option<unsafe extern "C-unwind" fn(ctx: *mut c_void, size: usize) -> *mut u8>;
+
+/// An object found by an `AlpacaResolveFn`. `content_type` may be left NULL
+/// to keep the kind implied by the html element referencing the object.
+/// `css_refs` is the number of `url()` and `@import` references of a
+/// stylesheet, for which its target size leaves room; references beyond it
+/// may be left unpadded.
+#[repr(C)]
+pub struct AlpacaResolvedObject {
+    pub size: usize,
+    pub content_type: *const c_char,
+    pub css_refs: usize,
+}
+
+/// Resolver callback: finds the object at path `reference` (absolute and
+/// percent-decoded, without query or fragment) referenced by the page
+/// `page_uri` of `http_host`, filling `out`. Returns
+/// 1 if found, 0 if the object should not be morphed, negative on error.
+/// A panic of the callback fails the morphing call, like a panic of the library.
+pub type AlpacaResolveFn = Option<unsafe extern "C-unwind" fn(
+    ctx: *mut c_void,
+    http_host: *const c_char,
+    page_uri: *const c_char,
+    reference: *const c_char,
+    out: *mut AlpacaResolvedObject,
+) -> c_int>;
+
+/// Counters of the object cache, see `alpaca_cache_stats`.
+#[repr(C)]
+pub struct AlpacaCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// Preparsed morphing configuration, shared by all requests (and threads).
+/// Opaque to C, constructed with `alpaca_config_new`.
+pub struct AlpacaConfig {
+    morpher: Morpher,
+}
+
+/// The padding of an object, generated in chunks. Opaque to C, constructed
+/// with `alpaca_pad_reader_new`.
+pub struct AlpacaPadReader {
+    reader: PadReader<Arc<dyn PaddingSource>>,
+}
+
+/// Information about a request. Opaque to C,
+/// constructed with `alpaca_morph_info_new` and filled through the
+/// `alpaca_morph_info_set_*` functions. Strings are borrowed, they must
+/// outlive the morphing call. The warnings of the call are stored back in
+/// it, see `alpaca_morph_info_warning`.
+pub struct MorphInfo {
+    // request info
+    content: *const u8,     // u8 = uchar
+    size: usize,
+    root: *const u8,
+    uri: *const u8,
+    http_host: *const u8,
+    alias: usize,
+    query: *const u8,       // part after ?
+    content_type: *const u8,
+    content_encoding: *const u8,
+
+    // allocator of the output buffers
+    alloc: AlpacaAllocFn,
+    alloc_ctx: *mut c_void,
+
+    // warnings of the last morphing call
+    warnings: RefCell<Vec<CString>>,
+}
+
+/// Returns the version of the C interface implemented by the library.
+#[no_mangle]
+pub extern "C" fn alpaca_abi_version() -> u32 {
+    ALPACA_ABI_VERSION
+}
+
+/// Parses and validates a morphing configuration, once at server start. In
+/// probabilistic mode (`probabilistic` true) the three distributions are
+/// used, otherwise `obj_num`, `obj_size` and `max_obj_size`. Returns NULL if
+/// the configuration is invalid, the error is available through
+/// `alpaca_last_error_code` and `alpaca_last_error_message`.
+///
+/// # Safety
+///
+/// In probabilistic mode the distributions must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_new(
+    probabilistic: bool,
+    dist_html_size: *const c_char,
+    dist_obj_num: *const c_char,
+    dist_obj_size: *const c_char,
+    use_total_obj_size: bool,
+    obj_num: usize,
+    obj_size: usize,
+    max_obj_size: usize,
+) -> *mut AlpacaConfig {
+    ffi_call(|| {
+        let config = if probabilistic {
+            MorphConfig::Probabilistic(ProbabilisticConfig {
+                dist_html_size: Dist::from(c_string_to_str(dist_html_size as *const u8)?)?,
+                dist_obj_num: Dist::from(c_string_to_str(dist_obj_num as *const u8)?)?,
+                dist_obj_size: Dist::from(c_string_to_str(dist_obj_size as *const u8)?)?,
+                use_total_obj_size,
+            })
+        } else {
+            MorphConfig::Deterministic(DeterministicConfig { obj_num, obj_size, max_obj_size })
+        };
+        Ok(Box::into_raw(Box::new(AlpacaConfig { morpher: Morpher::new(config)? })))
+    }).unwrap_or(ptr::null_mut())
+}
+
+/// Loads a configuration file (see `Config::load`). Returns NULL if the file
+/// cannot be read or is invalid, the error (with the offending line) is
+/// available through `alpaca_last_error_code` and `alpaca_last_error_message`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_load(path: *const c_char) -> *mut AlpacaConfig {
+    ffi_call(|| {
+        let config = Config::load(c_string_to_str(path as *const u8)?)?;
+        Ok(Box::into_raw(Box::new(AlpacaConfig { morpher: Morpher::from_config(config)? })))
+    }).unwrap_or(ptr::null_mut())
+}
+
+/// Adds a policy to `config`: requests whose Host matches `host` and whose
+/// uri matches `path` are morphed using the default configuration of
+/// `policy`, instead of that of `config`. Either pattern may be NULL to match
+/// everything, patterns containing `*` or `?` are globs, other paths are
+/// prefixes. The most specific matching policy is used.
+///
+/// `policy` is consumed (even on failure) and must not be used afterwards.
+/// Returns 0 on failure, see `alpaca_last_error_code`.
+///
+/// # Safety
+///
+/// `config` and `policy` must be distinct configurations returned by
+/// `alpaca_config_new` or `alpaca_config_load`, and `config` must not be
+/// in use by other threads. `host` and `path` must be NULL or
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_add_policy(
+    config: *mut AlpacaConfig,
+    host: *const c_char,
+    path: *const c_char,
+    policy: *mut AlpacaConfig,
+) -> u8 {
+    ffi_call(|| {
+        if policy.is_null() || policy == config {
+            return Err(AlpacaError::InvalidArgument(String::from("NULL or self policy")));
+        }
+        let policy = Box::from_raw(policy).morpher.into_config();
+        let config = config.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaConfig")))?;
+
+        let pattern = |s: *const c_char| -> Result<Option<Pattern>, AlpacaError> {
+            if s.is_null() { Ok(None) } else { Ok(Some(Pattern::new(c_string_to_str(s as *const u8)?))) }
+        };
+        config.morpher.add_policy(Policy { host: pattern(host)?, path: pattern(path)?, morph: policy.morph })?;
+        Ok(1)
+    }).unwrap_or(0)
+}
+
+/// Sets a callback resolving the objects referenced by pages, for objects
+/// that are not static files under the document root (eg generated or
+/// proxied). Passing NULL restores the default resolver, which reads the
+/// files under the roots added with `alpaca_config_add_root`, or the one
+/// given in `alpaca_morph_info_set_request`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig` not in use by other threads.
+/// `resolve` is called concurrently from all threads using `config`, with
+/// the given `ctx`; it must be thread-safe, and `ctx` must outlive `config`.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_set_resolver(config: *mut AlpacaConfig, resolve: AlpacaResolveFn, ctx: *mut c_void) -> u8 {
+    ffi_call(|| {
+        let config = config.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaConfig")))?;
+        config.morpher.set_resolver(resolve.map(|resolve| Box::new(CResolver { resolve, ctx }) as Box<dyn ObjectResolver>));
+        Ok(1)
+    }).unwrap_or(0)
+}
+
+/// Adds a document root: objects whose uri starts with `prefix` are read from
+/// under `path` instead of the root of the request. If `alias` is true the
+/// prefix is replaced by `path` (like the `alias` directive of nginx),
+/// otherwise the whole uri is appended to it (like `root`). `$http_host` in
+/// `path` is replaced by the Host of the request. The longest matching
+/// prefix is used. Returns 0 on failure, see `alpaca_last_error_code`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig` not in use by other threads,
+/// `prefix` and `path` NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_add_root(config: *mut AlpacaConfig, prefix: *const c_char, path: *const c_char, alias: bool) -> u8 {
+    ffi_call(|| {
+        let config = config.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaConfig")))?;
+        config.morpher.add_root(DocumentRoot {
+            prefix: String::from(c_string_to_str(prefix as *const u8)?),
+            path: String::from(c_string_to_str(path as *const u8)?),
+            alias,
+        });
+        Ok(1)
+    }).unwrap_or(0)
+}
+
+/// Sets whether morphed pages are written by editing the original markup in
+/// place (keeping its formatting), instead of serializing the parsed page.
+/// Pages that cannot be edited in place are still serialized. Returns 0 on
+/// failure, see `alpaca_last_error_code`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig` not in use by other threads.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_set_in_place(config: *mut AlpacaConfig, in_place: bool) -> u8 {
+    ffi_call(|| {
+        let config = config.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaConfig")))?;
+        config.morpher.set_in_place(in_place);
+        Ok(1)
+    }).unwrap_or(0)
+}
+
+/// Sets where the padding of morphed pages is placed: `end` (a comment at
+/// the end, the default), `head` (a comment before `</head>`), `body` (a
+/// comment before `</body>`), `split` (comments before random tags), `div`
+/// (a hidden `<div>` before `</body>`) or `attribute` (a `data-` attribute
+/// of the `<body>`). Compressed pages are always padded at their end.
+/// Returns 0 on failure, see `alpaca_last_error_code`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig` not in use by other threads,
+/// `placement` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_set_html_padding(config: *mut AlpacaConfig, placement: *const c_char) -> u8 {
+    ffi_call(|| {
+        let config = config.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaConfig")))?;
+        let name = c_string_to_str(placement as *const u8)?;
+        let padding = HtmlPadding::from_name(name).ok_or_else(|| AlpacaError::InvalidArgument(format!("unknown html padding \"{}\"", name)))?;
+        config.morpher.set_html_padding(padding);
+        Ok(1)
+    }).unwrap_or(0)
+}
+
+/// Sets the generator of the content of paddings: `random` (bytes from a
+/// CSPRNG and alphanumeric text, the default), `alphanumeric`, `mimic` (text
+/// made of html and css tokens) or `seeded` (deterministic for a given
+/// `seed`, for reproducible tests only). `seed` is ignored by the others.
+/// Returns 0 on failure, see `alpaca_last_error_code`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig` not in use by other threads,
+/// `name` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_set_padding_source(config: *mut AlpacaConfig, name: *const c_char, seed: u64) -> u8 {
+    ffi_call(|| {
+        let config = config.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaConfig")))?;
+        let name = c_string_to_str(name as *const u8)?;
+        let source: Box<dyn PaddingSource> = match name {
+            "random" => Box::new(CsprngSource),
+            "alphanumeric" => Box::new(AlphanumericSource),
+            "mimic" => Box::new(MimicSource),
+            "seeded" => Box::new(SeededSource::new(seed)),
+            _ => return Err(AlpacaError::InvalidArgument(format!("unknown padding source \"{}\"", name))),
+        };
+        config.morpher.set_padding_source(source);
+        Ok(1)
+    }).unwrap_or(0)
+}
+
+/// Frees a configuration returned by `alpaca_config_new` or `alpaca_config_load`.
+///
+/// # Safety
+///
+/// `config` must be NULL or returned by `alpaca_config_new`, and not used
+/// by any thread afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_config_free(config: *mut AlpacaConfig) {
+    if !config.is_null() {
+        ffi_call(|| { drop(Box::from_raw(config)); Ok(()) });
+    }
+}
+
+/// Allocates an empty `MorphInfo`. `abi_version` must be `ALPACA_ABI_VERSION`
+/// as seen by the caller, otherwise NULL is returned.
+#[no_mangle]
+pub extern "C" fn alpaca_morph_info_new(abi_version: u32) -> *mut MorphInfo {
+    ffi_call(|| {
+        if abi_version != ALPACA_ABI_VERSION {
+            return Err(AlpacaError::InvalidArgument(format!("ABI version {} requested, library implements {}", abi_version, ALPACA_ABI_VERSION)));
+        }
+        Ok(Box::into_raw(Box::new(MorphInfo {
+            content: ptr::null(),
+            size: 0,
+            root: ptr::null(),
+            uri: ptr::null(),
+            http_host: ptr::null(),
+            alias: 0,
+            query: ptr::null(),
+            content_type: ptr::null(),
+            content_encoding: ptr::null(),
+            alloc: None,
+            alloc_ctx: ptr::null_mut(),
+            warnings: RefCell::new(Vec::new()),
+        })))
+    }).unwrap_or(ptr::null_mut())
+}
+
+/// Frees a `MorphInfo` allocated by `alpaca_morph_info_new`. The content it
+/// points to is not freed.
+///
+/// # Safety
+///
+/// `pinfo` must be NULL or returned by `alpaca_morph_info_new`.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_free(pinfo: *mut MorphInfo) {
+    if !pinfo.is_null() {
+        ffi_call(|| { drop(Box::from_raw(pinfo)); Ok(()) });
+    }
+}
+
+/// Sets the content to morph (html) or the size of the object to pad
+/// (`content` may be NULL in that case). The content may contain NUL bytes,
+/// pages are decoded according to `alpaca_morph_info_set_content_type`.
+/// Ignored if `pinfo` is NULL; cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be a valid `MorphInfo`, `content` must point to `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_set_content(pinfo: *mut MorphInfo, content: *const u8, size: usize) {
+    if let Some(info) = pinfo.as_mut() {
+        info.content = content;
+        info.size = size;
+    }
+}
+
+/// Sets the request information used to locate the objects of an html page.
+/// Ignored if `pinfo` is NULL; cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be a valid `MorphInfo`, strings must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_set_request(
+    pinfo: *mut MorphInfo,
+    root: *const c_char,
+    uri: *const c_char,
+    http_host: *const c_char,
+    alias: usize,
+) {
+    if let Some(info) = pinfo.as_mut() {
+        info.root = root as *const u8;
+        info.uri = uri as *const u8;
+        info.http_host = http_host as *const u8;
+        info.alias = alias;
+    }
+}
+
+/// Sets the Content-Type of an html page, whose charset (if any) is the
+/// encoding of the content. Otherwise the encoding is taken from the
+/// `<meta charset>` of the page, or defaults to UTF-8. The morphed page
+/// is returned in the same encoding.
+/// Ignored if `pinfo` is NULL; cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be a valid `MorphInfo`, `content_type` must be NULL or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_set_content_type(pinfo: *mut MorphInfo, content_type: *const c_char) {
+    if let Some(info) = pinfo.as_mut() {
+        info.content_type = content_type as *const u8;
+    }
+}
+
+/// Sets the query and content type of an object request.
+/// Ignored if `pinfo` is NULL; cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be a valid `MorphInfo`, strings must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_set_object(pinfo: *mut MorphInfo, query: *const c_char, content_type: *const c_char) {
+    if let Some(info) = pinfo.as_mut() {
+        info.query = query as *const u8;
+        info.content_type = content_type as *const u8;
+    }
+}
+
+/// Sets the Content-Encoding negotiated with the client (`gzip`, `deflate`
+/// or `br`). The morphing functions then return the content compressed,
+/// and the target sizes are those of the compressed content. `morph_object`
+/// needs the whole object, set with `alpaca_morph_info_set_content`, and
+/// returns it padded instead of the padding only. NULL or `identity`
+/// (the default) disables compression.
+/// Ignored if `pinfo` is NULL; cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be a valid `MorphInfo`, `content_encoding` must be NULL or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_set_compression(pinfo: *mut MorphInfo, content_encoding: *const c_char) {
+    if let Some(info) = pinfo.as_mut() {
+        info.content_encoding = content_encoding as *const u8;
+    }
+}
+
+/// Sets an allocator for the output buffers. When set, morphed pages and
+/// paddings are copied into memory obtained by `alloc(ctx, size)` (eg from
+/// a server pool) which the library never frees. Passing NULL restores the
+/// default (library-owned buffers, freed by `alpaca_buffer_free`).
+/// Ignored if `pinfo` is NULL; cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be a valid `MorphInfo`, `alloc` must return NULL or a
+/// pointer to at least `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_set_allocator(pinfo: *mut MorphInfo, alloc: AlpacaAllocFn, ctx: *mut c_void) {
+    if let Some(info) = pinfo.as_mut() {
+        info.alloc = alloc;
+        info.alloc_ctx = ctx;
+    }
+}
+
+/// Returns the number of warnings of the last `morph_html` or `morph_css`
+/// call with `pinfo`: problems that did not prevent morphing, eg objects
+/// that could not be resolved or padded and were left unchanged. Returns 0
+/// if `pinfo` is NULL; cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be NULL or a valid `MorphInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_warning_count(pinfo: *const MorphInfo) -> usize {
+    pinfo.as_ref().and_then(|info| info.warnings.try_borrow().ok()).map_or(0, |warnings| warnings.len())
+}
+
+/// Returns the message of warning `i` of the last morphing call, see
+/// `alpaca_morph_info_warning_count`, or NULL if there is no such warning.
+/// The string is owned by `pinfo`, it is valid until the next morphing
+/// call with `pinfo` or until it is freed. Cannot panic.
+///
+/// # Safety
+///
+/// `pinfo` must be NULL or a valid `MorphInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_morph_info_warning(pinfo: *const MorphInfo, i: usize) -> *const c_char {
+    pinfo.as_ref()
+        .and_then(|info| info.warnings.try_borrow().ok())
+        .and_then(|warnings| warnings.get(i).map(|msg| msg.as_ptr()))
+        .unwrap_or(ptr::null())
+}
+
+/// It samples a new page using the morphing mode of `config`, changes the
+/// references to its objects accordingly, and pads it. Objects that cannot
+/// be morphed are left unchanged, see `alpaca_morph_info_warning_count`.
+///
+/// Returns 1 on success, in which case the morphed page is stored in `out`.
+/// Returns 0 on failure (including a panic inside the library), leaving
+/// `out` untouched; the error is available through `alpaca_last_error_code`
+/// and `alpaca_last_error_message`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+/// `out` must point to an `AlpacaBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn morph_html(config: *const AlpacaConfig, pinfo: *const MorphInfo, out: *mut AlpacaBuffer) -> u8 {
+    ffi_call(|| {
+        let info = info_ref(pinfo)?;
+        let content = morph_html_info(config_ref(config)?, info)?;
+        buffer_to_c(content, info, out)
+    }).is_some() as u8
+}
+
+/// Returns the object's padding, or the whole padded object if a
+/// compression is set (see `alpaca_morph_info_set_compression`). Images
+/// and fonts are padded at their end, which strict decoders (eg of WOFF
+/// fonts) may reject; see `morph_object_content`. Compressed images and
+/// fonts cannot be padded.
+///
+/// Returns 1 on success, in which case the padding is stored in `out`.
+/// Returns 0 on failure (including a panic inside the library), leaving
+/// `out` untouched; the error is available through `alpaca_last_error_code`
+/// and `alpaca_last_error_message`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+/// `out` must point to an `AlpacaBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn morph_object(config: *const AlpacaConfig, pinfo: *const MorphInfo, out: *mut AlpacaBuffer) -> u8 {
+    ffi_call(|| {
+        let info = info_ref(pinfo)?;
+        let padding = morph_object_info(config_ref(config)?, info)?;
+        buffer_to_c(padding, info, out)
+    }).is_some() as u8
+}
+
+/// Like `morph_object`, returning the whole padded object, set with
+/// `alpaca_morph_info_set_content`. The padding of PNG, JPEG, GIF and WebP
+/// images, fonts and PDFs is inserted in their container (as an ancillary
+/// chunk, comment, private data or table), so that they remain valid;
+/// other objects are padded at their end. Compressed objects (see
+/// `alpaca_morph_info_set_compression`) are padded at their end, except
+/// images and fonts, which cannot be padded once compressed.
+///
+/// Returns 1 on success, in which case the object is stored in `out`.
+/// Returns 0 on failure, leaving `out` untouched; see `morph_object`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+/// `out` must point to an `AlpacaBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn morph_object_content(config: *const AlpacaConfig, pinfo: *const MorphInfo, out: *mut AlpacaBuffer) -> u8 {
+    ffi_call(|| {
+        let info = info_ref(pinfo)?;
+        let content = morph_object_content_info(config_ref(config)?, info)?;
+        buffer_to_c(content, info, out)
+    }).is_some() as u8
+}
+
+/// Like `morph_object`, returning a reader of the padding instead of the
+/// whole padding: `alpaca_pad_reader_read` generates it in chunks of the
+/// caller's size, directly in the caller's buffers (eg those of the
+/// response body), so that large paddings are never held in memory.
+/// Compressed objects cannot be read this way.
+///
+/// Returns NULL on failure, the error is available through
+/// `alpaca_last_error_code` and `alpaca_last_error_message`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`.
+/// The reader does not borrow them, it may outlive both.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_pad_reader_new(config: *const AlpacaConfig, pinfo: *const MorphInfo) -> *mut AlpacaPadReader {
+    ffi_call(|| {
+        let (config, info) = (config_ref(config)?, info_ref(pinfo)?);
+        if compression(info)?.is_some() {
+            return Err(AlpacaError::InvalidArgument(String::from("compressed objects cannot be read in chunks")));
+        }
+        let content_type = c_string_to_str(info.content_type)?;
+        let query = c_string_to_str(info.query)?;
+
+        let reader = config.morpher.morph_object_reader(content_type, info.size, query)?;
+        Ok(Box::into_raw(Box::new(AlpacaPadReader { reader })))
+    }).unwrap_or(ptr::null_mut())
+}
+
+/// Writes the next (at most `len`) bytes of the padding to `buf`. Returns
+/// the number of bytes written, less than `len` only at the end of the
+/// padding, 0 when it has been read entirely (or on failure).
+///
+/// # Safety
+///
+/// `reader` must be a valid `AlpacaPadReader` not in use by other threads,
+/// `buf` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_pad_reader_read(reader: *mut AlpacaPadReader, buf: *mut u8, len: usize) -> usize {
+    ffi_call(|| {
+        let reader = reader.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaPadReader")))?;
+        if len == 0 {
+            return Ok(0);
+        }
+        if buf.is_null() {
+            return Err(AlpacaError::InvalidArgument(String::from("NULL buffer")));
+        }
+        Ok(reader.reader.fill(slice::from_raw_parts_mut(buf, len)))
+    }).unwrap_or(0)
+}
+
+/// Returns the number of bytes of the padding left to read, 0 if `reader`
+/// is NULL. Cannot panic.
+///
+/// # Safety
+///
+/// `reader` must be NULL or a valid `AlpacaPadReader`.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_pad_reader_remaining(reader: *const AlpacaPadReader) -> usize {
+    reader.as_ref().map_or(0, |reader| reader.reader.remaining())
+}
+
+/// Frees a reader returned by `alpaca_pad_reader_new`.
+///
+/// # Safety
+///
+/// `reader` must be NULL or returned by `alpaca_pad_reader_new`, and not
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_pad_reader_free(reader: *mut AlpacaPadReader) {
+    if !reader.is_null() {
+        ffi_call(|| { drop(Box::from_raw(reader)); Ok(()) });
+    }
+}
+
+/// Morphs a stylesheet, set with `alpaca_morph_info_set_content`: the
+/// references to its objects (`url()`, `@import`) are changed like those of
+/// html pages, and it is padded to the target size of the query. The request
+/// fields are those of the stylesheet, and `query` must be set with
+/// `alpaca_morph_info_set_object`. Unlike `morph_object`, the whole morphed
+/// stylesheet is returned, to be served instead of the original.
+///
+/// Returns 1 on success, in which case the stylesheet is stored in `out`.
+/// Returns 0 on failure, leaving `out` untouched; see `morph_html`.
+///
+/// # Safety
+///
+/// `config` must be a valid `AlpacaConfig`, `pinfo` a valid `MorphInfo`,
+/// `out` must point to an `AlpacaBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn morph_css(config: *const AlpacaConfig, pinfo: *const MorphInfo, out: *mut AlpacaBuffer) -> u8 {
+    ffi_call(|| {
+        let info = info_ref(pinfo)?;
+        let content = morph_css_info(config_ref(config)?, info)?;
+        buffer_to_c(content, info, out)
+    }).is_some() as u8
+}
+
+/// Frees a buffer returned by the library and resets it to empty. Buffers
+/// placed in caller-allocated memory (`capacity` 0) are only reset.
+///
+/// # Safety
+///
+/// `buf` must be NULL or point to an `AlpacaBuffer` filled by the library.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_buffer_free(buf: *mut AlpacaBuffer) {
+    if let Some(buf) = buf.as_mut() {
+        ffi_call(|| {
+            if buf.capacity != 0 {
+                drop(Vec::from_raw_parts(buf.data, buf.len, buf.capacity));
+            }
+            *buf = AlpacaBuffer::empty();
+            Ok(())
+        });
+    }
+}
+
+/// Drops the cached size of the object file `path` (an absolute path, of the
+/// file or of a symlink to it), or of all objects if `path` is NULL.
+/// Cached entries are also dropped automatically when the files change.
+///
+/// # Safety
+///
+/// `path` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_cache_invalidate(path: *const c_char) {
+    ffi_call(|| {
+        let path = if path.is_null() { None } else { Some(Path::new(c_string_to_str(path as *const u8)?)) };
+        // entries are keyed by the files, with symlinks followed
+        let canonical = path.map(|path| fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)));
+        ObjectCache::global().invalidate(canonical.as_deref());
+        Ok(())
+    });
+}
+
+/// Stores the hit/miss counters and the number of entries of the object
+/// cache in `out`. Returns 0 if `out` is NULL.
+///
+/// # Safety
+///
+/// `out` must be NULL or point to an `AlpacaCacheStats`.
+#[no_mangle]
+pub unsafe extern "C" fn alpaca_cache_stats(out: *mut AlpacaCacheStats) -> u8 {
+    ffi_call(|| {
+        let out = out.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaCacheStats")))?;
+        let stats = ObjectCache::global().stats();
+        *out = AlpacaCacheStats { hits: stats.hits, misses: stats.misses, entries: stats.entries };
+        Ok(1)
+    }).unwrap_or(0)
+}
+
+/// Returns the code of the last error that occurred in the calling thread,
+/// or 0 if the last call succeeded. See `AlpacaError::code`. Cannot panic.
+#[no_mangle]
+pub extern "C" fn alpaca_last_error_code() -> c_int {
+    LAST_ERROR.try_with(|last| last.try_borrow().ok()?.as_ref().map(|&(code, _)| code))
+        .ok().flatten().unwrap_or(0)
+}
+
+/// Returns the message of the last error that occurred in the calling
+/// thread, or NULL if the last call succeeded. The string is owned by the
+/// library and is valid until the next call into it from the same thread.
+/// Cannot panic.
+#[no_mangle]
+pub extern "C" fn alpaca_last_error_message() -> *const c_char {
+    LAST_ERROR.try_with(|last| last.try_borrow().ok()?.as_ref().map(|(_, msg)| msg.as_ptr()))
+        .ok().flatten().unwrap_or(ptr::null())
+}
+
+unsafe fn morph_html_info(config: &AlpacaConfig, info: &MorphInfo) -> Result<Vec<u8>, AlpacaError> {
+    let ctx = request_context(info)?;
+    let content_type = if info.content_type.is_null() { None } else { Some(c_string_to_str(info.content_type)?) };
+
+    let page = config.morpher.morph_html_compressed(content_slice(info)?, content_type, compression(info)?, &ctx)?;
+    set_warnings(info, &page.warnings);
+    Ok(page.content)
+}
+
+unsafe fn morph_css_info(config: &AlpacaConfig, info: &MorphInfo) -> Result<Vec<u8>, AlpacaError> {
+    let ctx = request_context(info)?;
+    let css = str::from_utf8(content_slice(info)?)?;
+    let query = c_string_to_str(info.query)?;
+
+    let stylesheet = config.morpher.morph_css_compressed(css, &ctx, query, compression(info)?)?;
+    set_warnings(info, &stylesheet.warnings);
+    Ok(stylesheet.content)
+}
+
+fn set_warnings(info: &MorphInfo, warnings: &[AlpacaError]) {
+    *info.warnings.borrow_mut() = warnings.iter().map(error_message).collect();
+}
+
+unsafe fn request_context<'a>(info: &MorphInfo) -> Result<RequestContext<'a>, AlpacaError> {
+    Ok(RequestContext {
+        root: c_string_to_str(info.root)?,
+        uri: c_string_to_str(info.uri)?,
+        http_host: c_string_to_str(info.http_host)?,
+        alias: info.alias,
+    })
+}
+
+unsafe fn morph_object_info(config: &AlpacaConfig, info: &MorphInfo) -> Result<Vec<u8>, AlpacaError> {
+    let content_type = c_string_to_str(info.content_type)?;
+    let query = c_string_to_str(info.query)?;
+
+    match compression(info)? {
+        Some(compression) => config.morpher.morph_object_compressed(content_type, content_slice(info)?, query, compression),
+        None => config.morpher.morph_object(content_type, info.size, query),
+    }
+}
+
+unsafe fn morph_object_content_info(config: &AlpacaConfig, info: &MorphInfo) -> Result<Vec<u8>, AlpacaError> {
+    let content_type = c_string_to_str(info.content_type)?;
+    let query = c_string_to_str(info.query)?;
+    let content = content_slice(info)?;
+
+    match compression(info)? {
+        Some(compression) => config.morpher.morph_object_compressed(content_type, content, query, compression),
+        None => config.morpher.morph_object_content(content_type, content, query),
+    }
+}
+
+unsafe fn compression(info: &MorphInfo) -> Result<Option<Compression>, AlpacaError> {
+    if info.content_encoding.is_null() {
+        return Ok(None);
+    }
+    Compression::from_content_encoding(c_string_to_str(info.content_encoding)?)
+}
+
+// An ObjectResolver calling back into C.
+//
+struct CResolver {
+    resolve: unsafe extern "C-unwind" fn(*mut c_void, *const c_char, *const c_char, *const c_char, *mut AlpacaResolvedObject) -> c_int,
+    ctx: *mut c_void,
+}
+
+// the callback is required to be thread-safe, see alpaca_config_set_resolver
+unsafe impl Send for CResolver {}
+unsafe impl Sync for CResolver {}
+
+impl ObjectResolver for CResolver {
+    fn resolve(&self, ctx: &RequestContext, reference: &str) -> Result<Option<ResolvedObject>, AlpacaError> {
+        let c_string = |s: &str| CString::new(s).map_err(|_| AlpacaError::InvalidArgument(format!("NUL in {}", s)));
+        let http_host = c_string(ctx.http_host)?;
+        let page_uri = c_string(ctx.uri)?;
+        let c_reference = c_string(reference)?;
+
+        let mut out = AlpacaResolvedObject { size: 0, content_type: ptr::null(), css_refs: 0 };
+        let ret = unsafe { (self.resolve)(self.ctx, http_host.as_ptr(), page_uri.as_ptr(), c_reference.as_ptr(), &mut out) };
+        match ret {
+            0 => Ok(None),
+            1 => {
+                let kind = if out.content_type.is_null() {
+                    None
+                } else {
+                    Some(dom::parse_object_kind(unsafe { c_string_to_str(out.content_type as *const u8)? }))
+                };
+                Ok(Some(ResolvedObject { size: out.size, kind, path: None, css_refs: out.css_refs }))
+            },
+            _ => Err(AlpacaError::Io(String::from(reference), io::Error::other(format!("resolver callback returned {}", ret)))),
+        }
+    }
+}
+
+// Stores the content in out, either by handing over the Vec (freed later by
+// alpaca_buffer_free) or by copying it to memory of the caller's allocator.
+//
+unsafe fn buffer_to_c(content: Vec<u8>, info: &MorphInfo, out: *mut AlpacaBuffer) -> Result<(), AlpacaError> {
+    let out = out.as_mut().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL output buffer")))?;
+
+    *out = match info.alloc {
+        Some(alloc) if !content.is_empty() => {
+            let data = alloc(info.alloc_ctx, content.len());
+            if data.is_null() {
+                return Err(AlpacaError::Alloc(content.len()));
+            }
+            ptr::copy_nonoverlapping(content.as_ptr(), data, content.len());
+            AlpacaBuffer { data, len: content.len(), capacity: 0 }
+        },
+        _ if content.capacity() == 0 => AlpacaBuffer::empty(),
+        _ => {
+            let mut content = mem::ManuallyDrop::new(content);
+            AlpacaBuffer { data: content.as_mut_ptr(), len: content.len(), capacity: content.capacity() }
+        },
+    };
+    Ok(())
+}
+
+/// Runs `f`, turning both its errors and its panics into `None`, so that a
+/// panic never unwinds into the C caller. The error is recorded for
+/// `alpaca_last_error_code` / `alpaca_last_error_message`, nothing is printed.
+fn ffi_call<T, F>(f: F) -> Option<T>
+    where F: FnOnce() -> Result<T, AlpacaError>
+{
+    clear_last_error();
+
+    let res = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => Err(AlpacaError::Panic(panic_message(&*payload))),
+    };
+    match res {
+        Ok(val) => Some(val),
+        Err(e) => {
+            set_last_error(&e);
+            None
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        String::from(*msg)
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+unsafe fn config_ref<'a>(config: *const AlpacaConfig) -> Result<&'a AlpacaConfig, AlpacaError> {
+    config.as_ref().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL AlpacaConfig")))
+}
+
+unsafe fn info_ref<'a>(pinfo: *const MorphInfo) -> Result<&'a MorphInfo, AlpacaError> {
+    pinfo.as_ref().ok_or_else(|| AlpacaError::InvalidArgument(String::from("NULL MorphInfo")))
+}
+
+fn set_last_error(e: &AlpacaError) {
+    let msg = error_message(e);
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some((e.code(), msg)));
+}
+
+fn error_message(e: &AlpacaError) -> CString {
+    // messages never contain NUL, but don't lose the error if they do
+    CString::new(e.to_string().replace('\0', "")).unwrap_or_default()
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+unsafe fn content_slice<'a>(info: &MorphInfo) -> Result<&'a [u8], AlpacaError> {
+    if info.content.is_null() {
+        return Err(AlpacaError::InvalidArgument(String::from("NULL content")));
+    }
+    Ok(slice::from_raw_parts(info.content, info.size))
+}
+
+unsafe fn c_string_to_str<'a>(s: *const u8) -> Result<&'a str, AlpacaError> {
+    if s.is_null() {
+        return Err(AlpacaError::InvalidArgument(String::from("NULL string")));
+    }
+    Ok(CStr::from_ptr(s as *const c_char).to_str()?)
+}
@@ -0,0 +1,156 @@
+//! Pads fonts inside their container, so that they remain valid files: the
+//! private data block of WOFF and WOFF2 fonts, or a table of TrueType and
+//! OpenType (sfnt) fonts. Fonts of other (or malformed) formats are padded
+//! at their end, like other objects.
+use error::AlpacaError;
+use pad;
+use source::PaddingSource;
+
+// The header of WOFF and WOFF2 fonts, with the offsets of their length and
+// private data block fields.
+const WOFF_LENGTH: usize = 8;
+const WOFF_PRIV_OFFSET: usize = 36;
+const WOFF2_PRIV_OFFSET: usize = 40;
+
+// The header of sfnt fonts (version, number of tables and the fields of the
+// binary search of tables), then the table records (tag, checksum, offset
+// and length), sorted by tag.
+const SFNT_HEADER: usize = 12;
+const SFNT_RECORD: usize = 16;
+// a private tag, that sanitizers drop
+const SFNT_TABLE_TAG: &[u8] = b"alPa";
+// the checksum of the whole font, stored in the head table
+const SFNT_HEAD_TAG: &[u8] = b"head";
+const SFNT_CHECKSUM_ADJUSTMENT: usize = 8;
+const SFNT_CHECKSUM_MAGIC: u32 = 0xB1B0AFBA;
+
+/// The minimum padding of a font: a table record, the alignment of the
+/// table and one byte of it (the WOFF private block only needs 4 bytes).
+pub const MIN_FONT_PADDING: usize = SFNT_RECORD + 3 + 1;
+
+/// Pads the font `content` to `target_size`, inside its container if its
+/// format is known, otherwise at its end. Returns the whole padded font.
+pub fn pad_font(content: &[u8], target_size: usize, source: &dyn PaddingSource) -> Result<Vec<u8>, AlpacaError> {
+    if content.len() + MIN_FONT_PADDING > target_size {
+        return Err(AlpacaError::PaddingUnderflow { size: content.len() + MIN_FONT_PADDING, target_size });
+    }
+    let pad_len = target_size - content.len();
+
+    let padded = match content.get(..4) {
+        Some(b"wOFF") => pad_woff(content, WOFF_PRIV_OFFSET, pad_len, source),
+        Some(b"wOF2") => pad_woff(content, WOFF2_PRIV_OFFSET, pad_len, source),
+        Some(b"\x00\x01\x00\x00") | Some(b"OTTO") | Some(b"true") => pad_sfnt(content, pad_len, source),
+        _ => None,
+    };
+    let padded = padded.unwrap_or_else(|| [content, &pad::get_binary_padding(pad_len, source)[..]].concat());
+    debug_assert_eq!(padded.len(), target_size);
+    Ok(padded)
+}
+
+fn be16(content: &[u8], pos: usize) -> Option<usize> {
+    content.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+}
+
+fn be32(content: &[u8], pos: usize) -> Option<usize> {
+    content.get(pos..pos + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+// More bytes in the private data block, whose offset and length fields are
+// at `priv_field`.
+fn pad_woff(content: &[u8], priv_field: usize, pad_len: usize, source: &dyn PaddingSource) -> Option<Vec<u8>> {
+    if be32(content, WOFF_LENGTH)? != content.len() {
+        return None;
+    }
+
+    let mut padded = content.to_vec();
+    let (priv_offset, priv_length) = match (be32(content, priv_field)?, be32(content, priv_field + 4)?) {
+        // no block, a new one starts at the next 4-byte boundary
+        (_, 0) => {
+            let offset = content.len().next_multiple_of(4);
+            padded.resize(offset, 0);
+            (offset, content.len() + pad_len - offset)
+        },
+        // the block is last, it is extended
+        (offset, length) if offset + length == content.len() => (offset, length + pad_len),
+        _ => return None,
+    };
+    padded.extend(pad::get_binary_padding(content.len() + pad_len - padded.len(), source));
+
+    let mut set_field = |pos: usize, value: usize| padded[pos..pos + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    set_field(WOFF_LENGTH, content.len() + pad_len);
+    set_field(priv_field, priv_offset);
+    set_field(priv_field + 4, priv_length);
+    Some(padded)
+}
+
+// A table at the end of the font. Its record moves the tables by 16 bytes,
+// their offsets and the checksum of the font are updated.
+fn pad_sfnt(content: &[u8], pad_len: usize, source: &dyn PaddingSource) -> Option<Vec<u8>> {
+    let num_tables = be16(content, 4)?;
+    if num_tables == u16::MAX as usize {
+        return None;
+    }
+    let dir_end = SFNT_HEADER + num_tables * SFNT_RECORD;
+    let records = content.get(SFNT_HEADER..dir_end)?;
+    for record in records.chunks(SFNT_RECORD) {
+        let (offset, length) = (be32(record, 8)?, be32(record, 12)?);
+        if &record[..4] == SFNT_TABLE_TAG || offset < dir_end || offset + length > content.len() {
+            return None;
+        }
+    }
+
+    let table_offset = (content.len() + SFNT_RECORD).next_multiple_of(4);
+    let table = pad::get_binary_padding(content.len() + pad_len - table_offset, source);
+    let mut table_record = Vec::from(SFNT_TABLE_TAG);
+    table_record.extend_from_slice(&checksum(&table).to_be_bytes());
+    table_record.extend_from_slice(&(table_offset as u32).to_be_bytes());
+    table_record.extend_from_slice(&(table.len() as u32).to_be_bytes());
+
+    // the binary search fields depend on the number of tables
+    let tables = num_tables + 1;
+    let entry_selector = tables.ilog2() as usize;
+    let search_range = SFNT_RECORD << entry_selector;
+    let mut padded = Vec::with_capacity(content.len() + pad_len);
+    padded.extend_from_slice(&content[..4]);
+    for field in [tables, search_range, entry_selector, tables * SFNT_RECORD - search_range] {
+        padded.extend_from_slice(&(field as u16).to_be_bytes());
+    }
+
+    let pos = records.chunks(SFNT_RECORD).position(|record| &record[..4] > SFNT_TABLE_TAG).unwrap_or(num_tables);
+    let mut head = None;
+    for (i, record) in records.chunks(SFNT_RECORD).enumerate() {
+        if i == pos {
+            padded.extend_from_slice(&table_record);
+        }
+        let offset = be32(record, 8)? + SFNT_RECORD;
+        if &record[..4] == SFNT_HEAD_TAG && be32(record, 12)? >= SFNT_CHECKSUM_ADJUSTMENT + 4 {
+            head = Some(offset);
+        }
+        padded.extend_from_slice(&record[..8]);
+        padded.extend_from_slice(&(offset as u32).to_be_bytes());
+        padded.extend_from_slice(&record[12..]);
+    }
+    if pos == num_tables {
+        padded.extend_from_slice(&table_record);
+    }
+    padded.extend_from_slice(&content[dir_end..]);
+    padded.resize(table_offset, 0);
+    padded.extend(table);
+
+    if let Some(head) = head {
+        let field = head + SFNT_CHECKSUM_ADJUSTMENT..head + SFNT_CHECKSUM_ADJUSTMENT + 4;
+        padded[field.clone()].copy_from_slice(&[0; 4]);
+        let adjustment = SFNT_CHECKSUM_MAGIC.wrapping_sub(checksum(&padded));
+        padded[field].copy_from_slice(&adjustment.to_be_bytes());
+    }
+    Some(padded)
+}
+
+// The sum of the big-endian 32-bit words of `data`, padded with zeros.
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, word| {
+        let mut bytes = [0; 4];
+        bytes[..word.len()].copy_from_slice(word);
+        sum.wrapping_add(u32::from_be_bytes(bytes))
+    })
+}
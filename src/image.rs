@@ -0,0 +1,281 @@
+//! Pads images inside their container, so that they remain valid files: a
+//! PNG ancillary chunk, JPEG comment segments, a GIF comment extension or a
+//! WebP RIFF chunk. Images of other (or malformed) formats are padded at
+//! their end, like other objects.
+use std::path::Path;
+use crc32fast::Hasher;
+use error::AlpacaError;
+use pad;
+use source::PaddingSource;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// ancillary, private, safe-to-copy
+const PNG_CHUNK_TYPE: &[u8] = b"alPa";
+const PNG_CHUNK_HEADER: usize = 12;     // length, type and crc
+
+const JPEG_SOI: &[u8] = &[0xFF, 0xD8];
+const JPEG_COM: u8 = 0xFE;
+const JPEG_SEGMENT: usize = 65535;      // including the marker and length
+const JPEG_SEGMENT_HEADER: usize = 4;
+
+const GIF_COMMENT: &[u8] = &[0x21, 0xFE];
+const GIF_TRAILER: u8 = 0x3B;
+// the extension introducer, label and terminator, plus a 1-byte sub-block
+const GIF_MIN_PADDING: usize = 5;
+
+const WEBP_CHUNK_TYPE: &[u8] = b"ALPA";
+const WEBP_CHUNK_HEADER: usize = 8;
+const WEBP_VP8X_SIZE: usize = 18;       // the VP8X chunk, added to simple files
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+
+/// The largest minimum padding of the image formats.
+pub const MIN_IMAGE_PADDING: usize = WEBP_VP8X_SIZE + WEBP_CHUNK_HEADER;
+
+/// Image formats padded inside their container.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+}
+
+impl ImageFormat {
+    /// The format of `content`, from its signature.
+    pub fn detect(content: &[u8]) -> Option<ImageFormat> {
+        if content.starts_with(PNG_SIGNATURE) {
+            Some(ImageFormat::Png)
+        } else if content.starts_with(JPEG_SOI) {
+            Some(ImageFormat::Jpeg)
+        } else if content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+            Some(ImageFormat::WebP)
+        } else {
+            None
+        }
+    }
+
+    /// The format of the image file `path`, from its extension.
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "gif" => Some(ImageFormat::Gif),
+            "webp" => Some(ImageFormat::WebP),
+            _ => None,
+        }
+    }
+
+    /// The minimum padding of an image of this format.
+    pub fn min_padding(&self) -> usize {
+        match *self {
+            ImageFormat::Png => PNG_CHUNK_HEADER,
+            ImageFormat::Jpeg => JPEG_SEGMENT_HEADER,
+            ImageFormat::Gif => GIF_MIN_PADDING,
+            ImageFormat::WebP => MIN_IMAGE_PADDING,
+        }
+    }
+
+    /// The padded sizes of images of this format are multiples of this
+    /// step: RIFF chunks have even sizes, and so do WebP files.
+    pub fn size_step(&self) -> usize {
+        match *self {
+            ImageFormat::WebP => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Pads the image `content` to `target_size`, inside its container if its
+/// format is known, otherwise at its end. Returns the whole padded image.
+/// WebP images can only be padded to even sizes (see `ImageFormat::size_step`).
+pub fn pad_image(content: &[u8], target_size: usize, source: &dyn PaddingSource) -> Result<Vec<u8>, AlpacaError> {
+    if target_size <= content.len() {
+        return Err(AlpacaError::PaddingUnderflow { size: content.len(), target_size });
+    }
+    let pad_len = target_size - content.len();
+
+    let padded = match ImageFormat::detect(content) {
+        Some(format) if pad_len < format.min_padding() => {
+            return Err(AlpacaError::PaddingUnderflow { size: content.len() + format.min_padding(), target_size });
+        },
+        Some(format) if !target_size.is_multiple_of(format.size_step()) => {
+            return Err(AlpacaError::InvalidArgument(format!("{:?} images cannot be padded to {} bytes", format, target_size)));
+        },
+        Some(ImageFormat::Png) => pad_png(content, pad_len, source),
+        Some(ImageFormat::Jpeg) => pad_jpeg(content, pad_len, source),
+        Some(ImageFormat::Gif) => pad_gif(content, pad_len, source),
+        Some(ImageFormat::WebP) => pad_webp(content, pad_len, source),
+        None => None,
+    };
+    let padded = padded.unwrap_or_else(|| {
+        let mut padded = content.to_vec();
+        padded.extend(pad::get_binary_padding(pad_len, source));
+        padded
+    });
+    debug_assert_eq!(padded.len(), target_size);
+    Ok(padded)
+}
+
+// Inserts `insert` at `pos` of `content`.
+fn splice(content: &[u8], pos: usize, insert: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len() + insert.len());
+    out.extend_from_slice(&content[..pos]);
+    out.extend_from_slice(insert);
+    out.extend_from_slice(&content[pos..]);
+    out
+}
+
+fn be16(content: &[u8], pos: usize) -> Option<usize> {
+    content.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+}
+
+fn be32(content: &[u8], pos: usize) -> Option<usize> {
+    content.get(pos..pos + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+fn le32(content: &[u8], pos: usize) -> Option<usize> {
+    content.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+// A chunk before IEND.
+fn pad_png(content: &[u8], pad_len: usize, source: &dyn PaddingSource) -> Option<Vec<u8>> {
+    let data_len = pad_len - PNG_CHUNK_HEADER;
+    if data_len > i32::MAX as usize {
+        return None;
+    }
+
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let len = be32(content, pos)?;
+        let chunk_type = content.get(pos + 4..pos + 8)?;
+        if chunk_type == b"IEND" {
+            break;
+        }
+        pos = pos.checked_add(len + PNG_CHUNK_HEADER)?;
+    }
+
+    let data = pad::get_binary_padding(data_len, source);
+    let mut hasher = Hasher::new();
+    hasher.update(PNG_CHUNK_TYPE);
+    hasher.update(&data);
+
+    let mut chunk = Vec::with_capacity(pad_len);
+    chunk.extend_from_slice(&(data_len as u32).to_be_bytes());
+    chunk.extend_from_slice(PNG_CHUNK_TYPE);
+    chunk.extend(data);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+    Some(splice(content, pos, &chunk))
+}
+
+// Comment segments after the APPn segments (JFIF and Exif require theirs to
+// come first).
+fn pad_jpeg(content: &[u8], pad_len: usize, source: &dyn PaddingSource) -> Option<Vec<u8>> {
+    let mut pos = JPEG_SOI.len();
+    loop {
+        if *content.get(pos)? != 0xFF {
+            return None;
+        }
+        match *content.get(pos + 1)? {
+            0xE0..=0xEF => pos += 2 + be16(content, pos + 2)?,
+            _ => break,
+        }
+    }
+
+    // segments of equal size, each at least JPEG_SEGMENT_HEADER long
+    let segments = pad_len.div_ceil(JPEG_SEGMENT);
+    let mut padding = Vec::with_capacity(pad_len);
+    let mut start = 0;
+    for i in 0..segments {
+        let end = pad_len * (i + 1) / segments;
+        padding.extend_from_slice(&[0xFF, JPEG_COM]);
+        padding.extend_from_slice(&((end - start - 2) as u16).to_be_bytes());
+        padding.extend(pad::get_binary_padding(end - start - JPEG_SEGMENT_HEADER, source));
+        start = end;
+    }
+    Some(splice(content, pos, &padding))
+}
+
+// A comment extension before the trailer.
+fn pad_gif(content: &[u8], pad_len: usize, source: &dyn PaddingSource) -> Option<Vec<u8>> {
+    if content.last() != Some(&GIF_TRAILER) {
+        return None;
+    }
+
+    // sub-blocks of 1 to 255 bytes, each with a length byte
+    let blocks_len = pad_len - GIF_COMMENT.len() - 1;
+    let blocks = blocks_len.div_ceil(256);
+    let data_len = blocks_len - blocks;
+    let mut padding = Vec::with_capacity(pad_len);
+    padding.extend_from_slice(GIF_COMMENT);
+    let mut start = 0;
+    for i in 0..blocks {
+        let end = data_len * (i + 1) / blocks;
+        padding.push((end - start) as u8);
+        padding.extend(pad::get_binary_padding(end - start, source));
+        start = end;
+    }
+    padding.push(0);
+
+    let mut padded = splice(content, content.len() - 1, &padding);
+    padded[3..6].copy_from_slice(b"89a");       // extensions were added in 89a
+    Some(padded)
+}
+
+// A chunk at the end of the RIFF container. Simple (VP8/VP8L) files cannot
+// have other chunks, they are converted to the extended format.
+fn pad_webp(content: &[u8], pad_len: usize, source: &dyn PaddingSource) -> Option<Vec<u8>> {
+    if le32(content, 4)? + 8 != content.len() || !content.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut padded = Vec::with_capacity(content.len() + pad_len);
+    padded.extend_from_slice(&content[..12]);
+    let mut chunk_len = pad_len - WEBP_CHUNK_HEADER;
+
+    let first_chunk = content.get(12..16)?;
+    if first_chunk != b"VP8X" {
+        padded.extend(webp_vp8x(content)?);
+        chunk_len -= WEBP_VP8X_SIZE;
+    }
+    padded.extend_from_slice(&content[12..]);
+
+    // the file and its target size are even (see pad_image), and so is the chunk
+    padded.extend_from_slice(WEBP_CHUNK_TYPE);
+    padded.extend_from_slice(&(chunk_len as u32).to_le_bytes());
+    padded.extend(pad::get_binary_padding(chunk_len, source));
+
+    let riff_size = padded.len() - 8;
+    padded[4..8].copy_from_slice(&(riff_size as u32).to_le_bytes());
+    Some(padded)
+}
+
+// The VP8X chunk of a simple file, with the canvas size of its bitstream.
+fn webp_vp8x(content: &[u8]) -> Option<Vec<u8>> {
+    let data = content.get(20..30)?;
+    let (width, height, flags) = match content.get(12..16)? {
+        b"VP8 " if data[3..6] == [0x9D, 0x01, 0x2A] => {
+            let width = u16::from_le_bytes([data[6], data[7]]) & 0x3FFF;
+            let height = u16::from_le_bytes([data[8], data[9]]) & 0x3FFF;
+            (width as u32, height as u32, 0)
+        },
+        b"VP8L" if data[0] == 0x2F => {
+            let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            let alpha = if bits & (1 << 28) != 0 { WEBP_ALPHA_FLAG } else { 0 };
+            ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, alpha)
+        },
+        _ => return None,
+    };
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let mut chunk = Vec::with_capacity(WEBP_VP8X_SIZE);
+    chunk.extend_from_slice(b"VP8X");
+    chunk.extend_from_slice(&10u32.to_le_bytes());
+    chunk.extend_from_slice(&[flags, 0, 0, 0]);
+    chunk.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    chunk.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    Some(chunk)
+}
@@ -0,0 +1,359 @@
+//! Edits the original html in place, instead of serializing the parsed
+//! document (which normalizes its markup). The tags of the html are paired
+//! with the elements of the document when it is parsed; after morphing, the
+//! changed attributes and text, and the appended elements, are written over
+//! the original bytes. Documents that cannot be paired reliably (eg when the
+//! parser moved or created elements) are left to `dom::serialize_html`.
+use std::ops::Range;
+use kuchiki::NodeRef;
+
+// Elements whose content is text, not markup.
+const RAW_TEXT: &[&str] = &["script", "style", "textarea", "title", "xmp", "iframe", "noembed", "noframes", "noscript", "plaintext"];
+
+// Elements that the parser creates even if their tags are omitted.
+const IMPLIED: &[&str] = &["html", "head", "body", "tbody", "colgroup"];
+
+// An attribute of a start tag.
+struct Attr {
+    name: String,
+    // End of the name, where a missing value is inserted
+    name_end: usize,
+    // The value, with its quotes
+    value: Option<Range<usize>>,
+}
+
+// A start tag of the html.
+struct Tag {
+    name: String,
+    start: usize,
+    // The first occurrence of each attribute, later ones are ignored by the parser
+    attrs: Vec<Attr>,
+    // The content of raw text elements
+    content: Option<Range<usize>>,
+}
+
+// An element of the parsed document, with its state before morphing.
+struct Element {
+    node: NodeRef,
+    // Index of its start tag, None for implied elements
+    tag: Option<usize>,
+    attrs: Vec<(String, String)>,
+    // The text of raw text elements
+    text: Option<String>,
+    // Whether the text is the content of the tag (bar newlines), so it can be replaced
+    verbatim: bool,
+}
+
+/// The original markup of a document, paired with its elements.
+pub struct SourceMap {
+    html: String,
+    tags: Vec<Tag>,
+    end_tags: Vec<(String, usize)>,
+    elements: Vec<Element>,
+}
+
+impl SourceMap {
+    /// Pairs the freshly parsed `document` with the `html` it was parsed
+    /// from. Returns None if some element or tag cannot be paired.
+    pub fn new(document: &NodeRef, html: &str) -> Option<SourceMap> {
+        let (tags, end_tags) = scan(html);
+        let mut elements = Vec::new();
+        let mut next = 0;
+
+        for node in document.descendants().filter(|node| node.as_element().is_some()) {
+            let name = node.as_element().unwrap().name.local.to_lowercase();
+            let attrs = attributes(&node);
+
+            let tag = match tags.get(next) {
+                Some(tag) if tag.name == name => {
+                    // the parser drops duplicate attributes, the rest must be the same
+                    let mut names: Vec<&str> = attrs.iter().map(|a| a.0.as_str()).collect();
+                    let mut tag_names: Vec<&str> = tag.attrs.iter().map(|a| a.name.as_str()).collect();
+                    names.sort_unstable();
+                    tag_names.sort_unstable();
+                    if names != tag_names {
+                        return None;
+                    }
+                    next += 1;
+                    Some(next - 1)
+                },
+                _ if IMPLIED.contains(&name.as_str()) => None,
+                _ => return None,
+            };
+
+            let content = tag.and_then(|i| tags[i].content.clone());
+            let text = content.as_ref().map(|_| node.text_contents());
+            let verbatim = match (&text, content) {
+                (Some(text), Some(content)) => *text == normalize_newlines(&html[content]),
+                _ => false,
+            };
+
+            elements.push(Element { node, tag, attrs, text, verbatim });
+        }
+
+        // all tags must have created an element
+        if next != tags.len() {
+            return None;
+        }
+        Some(SourceMap { html: String::from(html), tags, end_tags, elements })
+    }
+
+    /// Applies the changes of `document` since it was parsed to the original
+    /// html. Returns None if they cannot be made in place.
+    pub fn rewrite(&self, document: &NodeRef) -> Option<Vec<u8>> {
+        let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+        let mut added: Vec<NodeRef> = Vec::new();
+        let mut known = self.elements.iter().peekable();
+
+        for node in document.descendants().filter(|node| node.as_element().is_some()) {
+            match known.peek() {
+                Some(elem) if elem.node == node => {
+                    self.edits(elem, &mut edits)?;
+                    known.next();
+                },
+                // the children of added elements are serialized with them
+                _ if node.ancestors().any(|a| added.contains(&a)) => {},
+                _ => {
+                    let pos = self.insertion_point(&node.parent()?)?;
+                    edits.push((pos..pos, node.to_string()));
+                    added.push(node);
+                },
+            }
+        }
+        // elements were removed or moved
+        if known.next().is_some() {
+            return None;
+        }
+
+        // insertions at the same position keep their order
+        edits.sort_by_key(|edit| edit.0.start);
+        let mut out = Vec::with_capacity(self.html.len() + edits.iter().map(|e| e.1.len()).sum::<usize>());
+        let mut pos = 0;
+        for (range, text) in edits {
+            if range.start < pos {
+                return None;
+            }
+            out.extend_from_slice(&self.html.as_bytes()[pos..range.start]);
+            out.extend_from_slice(text.as_bytes());
+            pos = range.end;
+        }
+        out.extend_from_slice(&self.html.as_bytes()[pos..]);
+        Some(out)
+    }
+
+    // Pushes the edits of the changed attributes and text of `elem`.
+    fn edits(&self, elem: &Element, edits: &mut Vec<(Range<usize>, String)>) -> Option<()> {
+        let attrs = attributes(&elem.node);
+        let text = elem.text.as_ref().map(|_| elem.node.text_contents());
+        if attrs == elem.attrs && text == elem.text {
+            return Some(());
+        }
+        // changed implied elements have no tag to edit
+        let tag = &self.tags[elem.tag?];
+
+        for (name, value) in &attrs {
+            if elem.attrs.iter().any(|a| a.0 == *name && a.1 == *value) {
+                continue;
+            }
+            let escaped = format!("\"{}\"", escape_attribute(value));
+            match tag.attrs.iter().find(|a| a.name == *name) {
+                Some(&Attr { value: Some(ref range), .. }) => edits.push((range.clone(), escaped)),
+                Some(attr) => edits.push((attr.name_end..attr.name_end, format!("={}", escaped))),
+                None => {
+                    let pos = tag.start + 1 + tag.name.len();
+                    edits.push((pos..pos, format!(" {}={}", name, escaped)));
+                },
+            }
+        }
+        // removed attributes
+        if elem.attrs.iter().any(|a| !attrs.iter().any(|b| b.0 == a.0)) {
+            return None;
+        }
+
+        if text != elem.text {
+            if !elem.verbatim {
+                return None;
+            }
+            edits.push((tag.content.clone()?, text?));
+        }
+        Some(())
+    }
+
+    // Where the elements appended to `parent` are inserted.
+    fn insertion_point(&self, parent: &NodeRef) -> Option<usize> {
+        let end_tag = |name: &str| self.end_tags.iter().find(|t| t.0 == name).map(|t| t.1);
+        let name = match parent.as_element() {
+            Some(elem) => elem.name.local.to_lowercase(),
+            None => return Some(self.html.len()),       // the document
+        };
+
+        match name.as_str() {
+            // before the content of the body, if </head> is omitted
+            "head" => end_tag("head").or_else(|| {
+                self.elements.iter()
+                    .filter(|elem| elem.node.inclusive_ancestors().any(|a| a.as_element().is_some_and(|e| &*e.name.local == "body")))
+                    .find_map(|elem| elem.tag.map(|i| self.tags[i].start))
+            }).or(Some(self.html.len())),
+            "body" => end_tag("body").or_else(|| end_tag("html")).or(Some(self.html.len())),
+            "html" => end_tag("html").or(Some(self.html.len())),
+            _ => None,
+        }
+    }
+}
+
+// The attributes of an element, with their (lowercase) qualified names.
+fn attributes(node: &NodeRef) -> Vec<(String, String)> {
+    let elem = node.as_element().unwrap();
+    let attrs = elem.attributes.borrow();
+    attrs.map.iter().map(|(name, attr)| {
+        let name = match attr.prefix {
+            Some(ref prefix) => format!("{}:{}", prefix, name.local),
+            None => name.local.to_string(),
+        };
+        (name.to_ascii_lowercase(), attr.value.clone())
+    }).collect()
+}
+
+// Escapes a double-quoted attribute value, like the html serializer.
+fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;").replace('\u{a0}', "&nbsp;").replace('"', "&quot;")
+}
+
+// The text of raw html, as seen by the parser.
+fn normalize_newlines(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Tags, as their lowercase name and position.
+pub type TagPositions = Vec<(String, usize)>;
+
+/// The positions (of the `<`) of the start and end tags of `html`, with
+/// their lowercase names, in order. Like the html tokenizer, comments and
+/// the content of raw text elements are skipped; the end tags of raw text
+/// elements are left out, markup inserted before them would be text.
+pub fn tag_positions(html: &str) -> (TagPositions, TagPositions) {
+    let (tags, mut end_tags) = scan(html);
+    end_tags.retain(|(name, _)| !RAW_TEXT.contains(&name.as_str()));
+    (tags.into_iter().map(|tag| (tag.name, tag.start)).collect(), end_tags)
+}
+
+// Finds the start and end tags of `html`, in order, following the html
+// tokenizer (comments and the content of raw text elements are skipped).
+fn scan(html: &str) -> (Vec<Tag>, Vec<(String, usize)>) {
+    let bytes = html.as_bytes();
+    let mut tags = Vec::new();
+    let mut end_tags = Vec::new();
+    let mut i = 0;
+
+    while let Some(pos) = bytes[i..].iter().position(|&b| b == b'<') {
+        i += pos;
+        let rest = &bytes[i + 1..];
+
+        if rest.starts_with(b"!--") {
+            i = find(bytes, i + 2, b"-->").map_or(bytes.len(), |end| end + 3);
+        } else if rest.starts_with(b"![CDATA[") {
+            i = find(bytes, i + 9, b"]]>").map_or(bytes.len(), |end| end + 3);
+        } else if rest.first().is_some_and(|&b| b == b'!' || b == b'?') {
+            i = find(bytes, i, b">").map_or(bytes.len(), |end| end + 1);
+        } else if rest.first() == Some(&b'/') && rest.get(1).is_some_and(u8::is_ascii_alphabetic) {
+            let end = name_end(bytes, i + 2);
+            end_tags.push((html[i + 2..end].to_ascii_lowercase(), i));
+            i = find(bytes, end, b">").map_or(bytes.len(), |end| end + 1);
+        } else if rest.first().is_some_and(u8::is_ascii_alphabetic) {
+            let (mut tag, end) = match start_tag(html, i) {
+                Some(tag) => tag,
+                None => break,      // tags cut by the end of the html are dropped
+            };
+            i = end;
+            if RAW_TEXT.contains(&tag.name.as_str()) {
+                let content_end = if tag.name == "plaintext" { bytes.len() } else { raw_text_end(bytes, i, &tag.name) };
+                tag.content = Some(i..content_end);
+                i = content_end;
+            }
+            tags.push(tag);
+        } else {
+            i += 1;
+        }
+    }
+    (tags, end_tags)
+}
+
+// Parses the start tag at `start`, returns it and its end.
+fn start_tag(html: &str, start: usize) -> Option<(Tag, usize)> {
+    let bytes = html.as_bytes();
+    let mut i = name_end(bytes, start + 1);
+    let mut tag = Tag { name: html[start + 1..i].to_ascii_lowercase(), start, attrs: Vec::new(), content: None };
+
+    loop {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        match bytes.get(i) {
+            None => return None,
+            Some(&b'>') => return Some((tag, i + 1)),
+            Some(_) => {},
+        }
+
+        // the first character of a name may be '='
+        let name_start = i;
+        i += 1;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"/>=".contains(&bytes[i]) {
+            i += 1;
+        }
+        let name = html[name_start..i].to_ascii_lowercase();
+        let name_end = i;
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let mut value = None;
+        if bytes.get(i) == Some(&b'=') {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let value_start = i;
+            match bytes.get(i) {
+                Some(&quote) if quote == b'"' || quote == b'\'' => {
+                    i = find(bytes, i + 1, &[quote])? + 1;
+                },
+                _ => {
+                    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                        i += 1;
+                    }
+                },
+            }
+            value = Some(value_start..i);
+        }
+
+        if !tag.attrs.iter().any(|a| a.name == name) {
+            tag.attrs.push(Attr { name, name_end, value });
+        }
+    }
+}
+
+// End of the content of a raw text element `name`, at its end tag.
+fn raw_text_end(bytes: &[u8], from: usize, name: &str) -> usize {
+    let mut i = from;
+    while let Some(pos) = find(bytes, i, b"</") {
+        let after = pos + 2 + name.len();
+        let matches = bytes.get(pos + 2..after).is_some_and(|n| n.eq_ignore_ascii_case(name.as_bytes()))
+            && bytes.get(after).is_none_or(|&b| b.is_ascii_whitespace() || b == b'/' || b == b'>');
+        if matches {
+            return pos;
+        }
+        i = pos + 2;
+    }
+    bytes.len()
+}
+
+fn name_end(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'/' && bytes[i] != b'>' {
+        i += 1;
+    }
+    i
+}
+
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    bytes.get(from..)?.windows(needle.len()).position(|w| w == needle).map(|pos| from + pos)
+}
@@ -6,10 +6,32 @@ extern crate rand;
 extern crate rand_distr;
 extern crate html5ever;
 extern crate kuchiki;
+#[macro_use]
+extern crate serde;
+extern crate toml;
+extern crate url;
+extern crate percent_encoding;
+extern crate encoding_rs;
+extern crate flate2;
+extern crate brotli;
+extern crate crc32fast;
 
 pub mod pad;
 pub mod dom;
 pub mod morphing;
 pub mod distribution;
 pub mod deterministic;
-pub mod aux;
+pub mod error;
+pub mod config;
+pub mod policy;
+pub mod resolver;
+pub mod cache;
+pub mod css;
+pub mod uri;
+pub mod inplace;
+pub mod charset;
+pub mod compress;
+pub mod image;
+pub mod font;
+pub mod source;
+pub mod ffi;
@@ -1,221 +1,475 @@
 //! Contains main morphing routines.
-use std::ffi::CStr;
-use pad::{get_html_padding, get_object_padding};
+use pad::{get_html_padding, get_object_padding, PadReader};
 use dom;
 use pad;
-use dom::{Object,ObjectKind};
-use distribution::{Dist, sample_ge, sample_pair_ge, sample_ge_many};
+use dom::{Object, ObjectKind};
+use css;
+use uri;
+use std::sync::Arc;
+use distribution::{sample_ge, sample_pair_ge, sample_ge_many};
 use deterministic::*;
-use aux::stringify_error;
+use inplace::SourceMap;
+use charset::Charset;
+use compress::Compression;
+use policy::Policy;
+use resolver::{ObjectResolver, FsResolver, DocumentRoot};
+use config::{Config, HtmlPadding, MorphConfig, ProbabilisticConfig, DeterministicConfig, DEFAULT_MAX_TARGET_SIZE};
+use error::AlpacaError;
+use source::{PaddingSource, CsprngSource};
 
 use kuchiki::NodeRef;
 
-#[repr(C)]
-pub struct MorphInfo {
-    // request info
-    content: *const u8,     // u8 = uchar
-    size: usize,
-    root: *const u8,
-    uri: *const u8,
-    http_host: *const u8,
-    alias: usize,
-    query: *const u8,       // part after ?
-    content_type: *const u8,
-
-    probabilistic: usize,   // boolean
-
-    // for probabilistic
-    dist_html_size: *const u8,
-    dist_obj_num: *const u8,
-    dist_obj_size: *const u8,
-    use_total_obj_size: usize,
-
-    // for deterministic
-    obj_num: usize,
-    obj_size: usize,
-    max_obj_size: usize,
+/// Information about the request being morphed.
+pub struct RequestContext<'a> {
+    /// Document root, `$http_host` is replaced by `http_host`
+    pub root: &'a str,
+    /// The uri of the requested page
+    pub uri: &'a str,
+    /// The Host of the request
+    pub http_host: &'a str,
+    /// Length of the uri prefix that is aliased to `root`
+    pub alias: usize,
 }
 
-/// It samples a new page using probabilistic morphing, changes the
-/// references to its objects accordingly, and pads it.
-#[no_mangle]
-pub extern "C" fn morph_html(pinfo: *mut MorphInfo) -> u8 {
+/// The result of morphing an html page (or a stylesheet).
+pub struct MorphedPage {
+    /// The morphed and padded html
+    pub content: Vec<u8>,
+    /// The size the html was padded to
+    pub target_size: usize,
+    /// Problems that did not prevent morphing, eg objects that could not be
+    /// resolved or padded and were left unchanged
+    pub warnings: Vec<AlpacaError>,
+}
 
-    
-    std::env::set_var("RUST_BACKTRACE", "full");
-    let info = unsafe { &mut *pinfo };
+/// Morphs html pages and pads objects according to a `Config`.
+pub struct Morpher {
+    config: Config,
+    fs_resolver: FsResolver,
+    resolver: Option<Box<dyn ObjectResolver>>,
+    padding_source: Arc<dyn PaddingSource>,
+}
 
-    let root = c_string_to_str(info.root).unwrap();
-    let uri = c_string_to_str(info.uri).unwrap();
-    let http_host = c_string_to_str(info.http_host).unwrap();
+impl Morpher {
+    /// Construct a morpher using the given morphing configuration for all
+    /// requests, which is validated once here.
+    pub fn new(morph: MorphConfig) -> Result<Morpher, AlpacaError> {
+        Morpher::from_config(Config::new(morph))
+    }
 
-    // /* Convert arguments into &str */
-    let html = match c_string_to_str(info.content) {
-        Ok(s) => s,
-        Err(e) => {
-            eprint!("libalpaca: cannot read html content of {}: {}\n", uri, e);
-            return 0;       // return NULL pointer if html cannot be converted to a string
-        }
-    };
+    /// Construct a morpher from a complete configuration (eg loaded from a
+    /// file with `Config::load`), which is validated once here.
+    pub fn from_config(config: Config) -> Result<Morpher, AlpacaError> {
+        config.validate()?;
+        let fs_resolver = FsResolver::with_roots(config.roots.clone());
+        Ok(Morpher { config, fs_resolver, resolver: None, padding_source: Arc::new(CsprngSource) })
+    }
+
+    /// The configuration used by this morpher.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Consumes the morpher, returning its configuration.
+    pub fn into_config(self) -> Config {
+        self.config
+    }
 
-    let document = dom::parse_html(html);
+    /// Replaces the resolver of the objects referenced by pages, by default
+    /// (or if None) `FsResolver` which reads them from the document roots.
+    pub fn set_resolver(&mut self, resolver: Option<Box<dyn ObjectResolver>>) {
+        self.resolver = resolver;
+    }
 
-    let full_root = String::from(root).replace("$http_host", http_host);
+    /// Adds a document root to the table of the default resolver.
+    pub fn add_root(&mut self, root: DocumentRoot) {
+        self.config.roots.push(root.clone());
+        self.fs_resolver.add_root(root);
+    }
 
-    let mut objects = dom::parse_objects(&document, full_root.as_str(), uri, info.alias); // Vector of objects found in the html.
-    let orig_n = objects.len(); // Number of original objects.
+    /// Whether pages are edited in place instead of serialized, see `HtmlConfig`.
+    pub fn set_in_place(&mut self, in_place: bool) {
+        self.config.html.in_place = in_place;
+    }
 
-    let target_size = match
-        if info.probabilistic != 0 {
-            morph_probabilistic(&document, &mut objects, &info)
-        } else {
-            morph_deterministic(&document, &mut objects, &info)
-        } {
-        Ok(s) => s,
-        Err(e) => {
-            eprint!("libalpaca: cannot morph: {}\n", e);
-            return document_to_c(&document, info);
+    /// Where the padding of pages is placed, see `HtmlConfig`.
+    pub fn set_html_padding(&mut self, padding: HtmlPadding) {
+        self.config.html.padding = padding;
+    }
+
+    /// Replaces the generator of the content of paddings, by default
+    /// `CsprngSource`.
+    pub fn set_padding_source(&mut self, source: Box<dyn PaddingSource>) {
+        self.padding_source = Arc::from(source);
+    }
+
+    fn resolver(&self) -> &dyn ObjectResolver {
+        self.resolver.as_deref().unwrap_or(&self.fs_resolver)
+    }
+
+    /// Adds a policy overriding the default configuration for the requests
+    /// it matches. The policy is validated here, like in `new`.
+    pub fn add_policy(&mut self, policy: Policy) -> Result<(), AlpacaError> {
+        policy.morph.validate()?;
+        self.config.policies.add(policy);
+        Ok(())
+    }
+
+    /// Samples a new page, changes the references to its objects
+    /// accordingly, and pads it.
+    pub fn morph_html(&self, html: &str, ctx: &RequestContext) -> Result<MorphedPage, AlpacaError> {
+        self.morph_page(html, Charset::default(), None, ctx)
+    }
+
+    /// Like `morph_html`, for a page in any (ASCII-compatible) encoding,
+    /// detected from `content` and its Content-Type (see `Charset::detect`).
+    /// The page is morphed and padded in its original encoding, the target
+    /// size is in bytes of that encoding.
+    pub fn morph_html_bytes(&self, content: &[u8], content_type: Option<&str>, ctx: &RequestContext) -> Result<MorphedPage, AlpacaError> {
+        self.morph_html_compressed(content, content_type, None, ctx)
+    }
+
+    /// Like `morph_html_bytes`, returning the page compressed with
+    /// `compression` (if any). The target size is then the compressed size.
+    pub fn morph_html_compressed(
+        &self,
+        content: &[u8],
+        content_type: Option<&str>,
+        compression: Option<Compression>,
+        ctx: &RequestContext,
+    ) -> Result<MorphedPage, AlpacaError> {
+        let charset = Charset::detect(content, content_type)?;
+        self.morph_page(&charset.decode(content), charset, compression, ctx)
+    }
+
+    fn morph_page(&self, html: &str, charset: Charset, compression: Option<Compression>, ctx: &RequestContext) -> Result<MorphedPage, AlpacaError> {
+        let document = dom::parse_html(html);
+        let source = if self.config.html.in_place { SourceMap::new(&document, html) } else { None };
+
+        let mut warnings = Vec::new();
+        let mut objects = dom::parse_objects(&document, ctx, self.resolver(), &mut warnings); // Vector of objects found in the html.
+
+        // the page is serialized if it cannot be edited in place (checked
+        // once the favicon is inserted)
+        let (source, content) = match source.and_then(|s| s.rewrite(&document).map(|content| (s, content))) {
+            Some((source, content)) => (Some(source), content),
+            None => (None, dom::serialize_html(&document)?),
+        };
+        let content = charset.encode(content)?;
+        let morph = self.config.morph_config_for(ctx.http_host, ctx.uri);
+        // only probabilistic pages are sampled from their size without the references
+        let html_size = match (compression, morph) {
+            (Some(compression), &MorphConfig::Probabilistic(_)) => compression.compress(&content)?.min_size(0),
+            _ => content.len(),
+        };
+
+        // stylesheets grow when their references are rewritten, see morph_css
+        for obj in objects.iter_mut().filter(|obj| obj.kind == ObjectKind::CSS) {
+            obj.size += css::rewrite_overhead(obj.css_refs, self.config.limits.max_target_size);
         }
-    };
+        let orig_n = objects.len(); // Number of original objects.
+
+        let target_size = match *morph {
+            MorphConfig::Probabilistic(ref config) => morph_probabilistic(html_size, &mut objects, config, &mut warnings)?,
+            MorphConfig::Deterministic(ref config) => { morph_deterministic(&mut objects, config)?; 0 },
+        };
+
+        // insert refs and add padding
+        insert_objects_refs(&document, &objects, orig_n)?;
+
+        let html = match source.and_then(|s| s.rewrite(&document)) {
+            Some(html) => html,
+            None => dom::serialize_html(&document)?,
+        };
+        let mut content = charset.encode(html.clone())?;
+        // compressed pages are padded at their end, see Compressed::pad
+        let placement = if compression.is_some() { HtmlPadding::End } else { self.config.html.padding };
+        let compressed = match compression {
+            Some(compression) => Some(compression.compress(&content)?),
+            None => None,
+        };
+        let min_size = match compressed {
+            Some(ref compressed) => compressed.min_size(pad::MIN_HTML_PADDING),
+            None => content.len() + pad::min_html_padding(placement),
+        };
+
+        // deterministic pages are padded according to their final size, with
+        // the references inserted; probabilistic ones are resampled if the
+        // size was underestimated (eg because of compression)
+        let target_size = match *morph {
+            MorphConfig::Deterministic(ref config) => get_multiple(config.obj_size, min_size),
+            MorphConfig::Probabilistic(ref config) if target_size < min_size => sample_ge(&config.dist_html_size, min_size)?,
+            MorphConfig::Probabilistic(_) => target_size,
+        };
+
+        match compressed {
+            Some(compressed) => content = compressed.pad(target_size, |pad_len| pad::html_padding(pad_len, &*self.padding_source))?,
+            None if placement == HtmlPadding::End => get_html_padding(&mut content, target_size, &*self.padding_source)?, // Pad the html to the target size.
+            None => {
+                // the padding is ASCII, its size is the same once encoded
+                let html = String::from_utf8(html).map_err(|e| e.utf8_error())?;
+                content = charset.encode(pad::place_html_padding(&html, target_size - content.len(), placement, &*self.padding_source)?.into_bytes())?;
+            },
+        }
+        Ok(MorphedPage { content, target_size, warnings })
+    }
 
-    // insert refs and add padding
-    match insert_objects_refs(&document, &objects, orig_n) {
-        Ok(_) => {},
-        Err(e) => {
-            eprint!("libalpaca: insert_objects_refs failed: {}\n", e);
-            return document_to_c(&document, info);
+    /// Morphs a stylesheet (linked or imported by a morphed page): the
+    /// references to its objects are changed like those of html pages, and
+    /// it is padded to the target size requested in `query`. `ctx.uri` is
+    /// the uri of the stylesheet. Returns the whole morphed stylesheet.
+    ///
+    /// The page reserved room for the references counted by the resolver
+    /// (`ResolvedObject::css_refs`); references that don't fit in the target
+    /// size (eg not counted by a custom resolver) are left unchanged, with a
+    /// warning.
+    pub fn morph_css(&self, content: &str, ctx: &RequestContext, query: &str) -> Result<MorphedPage, AlpacaError> {
+        self.morph_css_compressed(content, ctx, query, None)
+    }
+
+    /// Like `morph_css`, returning the stylesheet compressed with
+    /// `compression` (if any). The target size is then the compressed size.
+    pub fn morph_css_compressed(&self, content: &str, ctx: &RequestContext, query: &str, compression: Option<Compression>) -> Result<MorphedPage, AlpacaError> {
+        let max_target_size = self.config.limits.max_target_size;
+        let morph = self.config.morph_config_for(ctx.http_host, ctx.uri);
+
+        let base = uri::page_url(ctx.http_host, ctx.uri);
+        let refs = css::scan_refs(content);
+        let mut new_uris = Vec::with_capacity(refs.len());
+        let mut warnings = Vec::new();
+
+        // without compression, references are only rewritten while they fit
+        let target_size = match compression {
+            Some(_) => None,
+            None => Some(object_target_size(content.len(), query, max_target_size)?),
+        };
+        let mut size = content.len() + pad::min_padding(&ObjectKind::CSS);
+
+        for css_ref in &refs {
+            let res = match dom::resolve_ref(&css_ref.uri, &base, ctx, self.resolver()) {
+                Ok(Some(res)) => res,
+                Ok(None) => continue,
+                Err(e) => { warnings.push(e); continue },
+            };
+            let kind = res.kind.unwrap_or(if css_ref.import { ObjectKind::CSS } else { ObjectKind::Unknown });
+            let step = pad::size_step(&kind, res.path.as_deref(), &css_ref.uri);
+            let mut min_size = res.size + pad::min_padding(&kind);
+            if kind == ObjectKind::CSS {
+                min_size += css::rewrite_overhead(res.css_refs, max_target_size);
+            }
+
+            let object_size = match *morph {
+                // the total object size is sampled per page, objects of stylesheets are left unchanged
+                MorphConfig::Probabilistic(ref config) if config.use_total_obj_size => continue,
+                MorphConfig::Probabilistic(ref config) => match sample_ge(&config.dist_obj_size, min_size) {
+                    Ok(size) => size.next_multiple_of(step),
+                    Err(e) => {
+                        warnings.push(unpadded(&css_ref.uri, e));
+                        continue;
+                    },
+                },
+                MorphConfig::Deterministic(ref config) => get_multiple(step_multiple(config.obj_size, step), min_size),
+            };
+            let new_uri = uri::padded_uri(&css_ref.uri, object_size);
+            if let Some(target_size) = target_size {
+                let new_size = size + new_uri.len() - css_ref.uri.len();
+                if new_size > target_size {
+                    warnings.push(unpadded(&css_ref.uri, AlpacaError::PaddingUnderflow { size: new_size, target_size }));
+                    continue;
+                }
+                size = new_size;
+            }
+            new_uris.push((css_ref, new_uri));
         }
+
+        let mut morphed = css::rewrite(content, &new_uris).into_bytes();
+        match compression {
+            Some(compression) => morphed = compress_object("text/css", &morphed, query, compression, max_target_size, &*self.padding_source)?,
+            None => {
+                let padding = pad_object("text/css", morphed.len(), query, max_target_size, &*self.padding_source)?;
+                morphed.extend(padding);
+            },
+        }
+        Ok(MorphedPage { target_size: morphed.len(), content: morphed, warnings })
+    }
+
+    /// Returns the padding of an object of the given content type and size,
+    /// requested with the given query (which contains the target size).
+    /// Images and fonts are padded at their end, which strict decoders (eg
+    /// of WOFF fonts) may reject, see `morph_object_content`.
+    pub fn morph_object(&self, content_type: &str, size: usize, query: &str) -> Result<Vec<u8>, AlpacaError> {
+        pad_object(content_type, size, query, self.config.limits.max_target_size, &*self.padding_source)
+    }
+
+    /// Like `morph_object`, returning a reader that generates the padding in
+    /// chunks instead of the whole padding, eg for large target sizes.
+    pub fn morph_object_reader(&self, content_type: &str, size: usize, query: &str) -> Result<PadReader<Arc<dyn PaddingSource>>, AlpacaError> {
+        let kind = dom::parse_object_kind(content_type);
+        let target_size = object_target_size(size, query, self.config.limits.max_target_size)?;
+
+        PadReader::new(kind, size, target_size, self.padding_source.clone())
     }
 
-    let mut content = dom::serialize_html(&document);
-    get_html_padding(&mut content, target_size); // Pad the html to the target size.
+    /// Returns the whole object `content`, padded to the target size
+    /// requested in `query`. Unlike the padding returned by `morph_object`,
+    /// that of images, fonts and PDFs is inserted in their container, so
+    /// that they remain valid (see `pad::pad_object_content`).
+    pub fn morph_object_content(&self, content_type: &str, content: &[u8], query: &str) -> Result<Vec<u8>, AlpacaError> {
+        pad_object_content(content_type, content, query, self.config.limits.max_target_size, &*self.padding_source)
+    }
 
-    return content_to_c(content, info);
+    /// Returns the whole object `content`, padded and compressed with
+    /// `compression` to the (compressed) target size requested in `query`.
+    /// Images and fonts cannot be padded once compressed.
+    pub fn morph_object_compressed(&self, content_type: &str, content: &[u8], query: &str, compression: Compression) -> Result<Vec<u8>, AlpacaError> {
+        compress_object(content_type, content, query, compression, self.config.limits.max_target_size, &*self.padding_source)
+    }
 }
 
-/// Returns the object's padding.
-#[no_mangle]
-pub extern "C" fn morph_object(pinfo: *mut MorphInfo) -> u8 {
+/// Returns the padding of an object. Objects are padded to the target size
+/// requested in the query, so no configuration is needed.
+pub fn morph_object(content_type: &str, size: usize, query: &str) -> Result<Vec<u8>, AlpacaError> {
+    pad_object(content_type, size, query, DEFAULT_MAX_TARGET_SIZE, &CsprngSource)
+}
 
-    let info = unsafe { &mut *pinfo };
+fn pad_object(content_type: &str, size: usize, query: &str, max_target_size: usize, source: &dyn PaddingSource) -> Result<Vec<u8>, AlpacaError> {
+    let kind = dom::parse_object_kind(content_type);
+    let target_size = object_target_size(size, query, max_target_size)?;
 
-    let content_type = c_string_to_str(info.content_type).unwrap();
-    let query = c_string_to_str(info.query).unwrap();
+    get_object_padding(kind, size, target_size, source) // Get the padding for the object.
+}
 
+fn pad_object_content(content_type: &str, content: &[u8], query: &str, max_target_size: usize, source: &dyn PaddingSource) -> Result<Vec<u8>, AlpacaError> {
     let kind = dom::parse_object_kind(content_type);
+    let target_size = object_target_size(content.len(), query, max_target_size)?;
 
+    pad::pad_object_content(kind, content, target_size, source)
+}
+
+// The target size requested in the query of an object of `size` bytes.
+fn object_target_size(size: usize, query: &str, max_target_size: usize) -> Result<usize, AlpacaError> {
     let target_size = dom::parse_target_size(query);
-    if (target_size == 0) || (target_size <= info.size) {
+    if (target_size == 0) || (target_size <= size) {
         // Target size has to be greater than current size.
-        eprint!("alpaca: morph_object: target_size ({}) cannot match current size ({})\n", target_size, info.size);
-        return content_to_c(Vec::new(), info);
+        return Err(AlpacaError::PaddingUnderflow { size, target_size });
     }
-    if target_size > 1000000000 {
-        // Target size too big
-        eprint!("alpaca: morph_object: target_size ({}) more than 1GB, probably wrong configuration or an alpaca bug\n", target_size);
-        return content_to_c(Vec::new(), info);
+    if target_size > max_target_size {
+        return Err(AlpacaError::TargetTooLarge(target_size));
     }
-
-    let padding = get_object_padding(kind, info.size, target_size); // Get the padding for the object.
-
-    return content_to_c(padding, info);
+    Ok(target_size)
 }
 
-/// Frees memory allocated in rust.
-#[no_mangle]
-pub extern "C" fn free_memory(data: *mut u8, size: usize) {
+fn compress_object(
+    content_type: &str,
+    content: &[u8],
+    query: &str,
+    compression: Compression,
+    max_target_size: usize,
+    source: &dyn PaddingSource,
+) -> Result<Vec<u8>, AlpacaError> {
+    let kind = match dom::parse_object_kind(content_type) {
+        // the padding follows the content once decompressed: text formats
+        // keep their syntax, others get raw bytes
+        kind @ (ObjectKind::CSS | ObjectKind::JS | ObjectKind::SVG | ObjectKind::JSON | ObjectKind::Wasm | ObjectKind::PDF) => kind,
+        // their padding belongs inside their container, not after it
+        ObjectKind::IMG => return Err(AlpacaError::InvalidArgument(String::from("compressed images cannot be padded"))),
+        ObjectKind::Font => return Err(AlpacaError::InvalidArgument(String::from("compressed fonts cannot be padded"))),
+        _ => ObjectKind::Unknown,
+    };
 
-    let s = unsafe { std::slice::from_raw_parts_mut(data, size) };
-    let s = s.as_mut_ptr();
-    unsafe {
-        Box::from_raw(s);
+    let target_size = dom::parse_target_size(query);
+    if target_size > max_target_size {
+        return Err(AlpacaError::TargetTooLarge(target_size));
     }
+    let compressed = compression.compress(content)?;
+    let min_size = compressed.min_size(pad::min_padding(&kind));
+    if target_size < min_size {
+        return Err(AlpacaError::PaddingUnderflow { size: min_size, target_size });
+    }
+    compressed.pad(target_size, |pad_len| get_object_padding(kind, 0, pad_len, source))
 }
 
 fn morph_probabilistic (
-    document: &NodeRef,
+    html_size: usize,
     objects: &mut Vec<Object>,
-    info: &MorphInfo,
-) -> Result<usize, String> {
+    config: &ProbabilisticConfig,
+    warnings: &mut Vec<AlpacaError>,
+) -> Result<usize, AlpacaError> {
 
-    let dist_html_size = Dist::from(c_string_to_str(info.dist_html_size)?)?;
-    let dist_obj_num = Dist::from(c_string_to_str(info.dist_obj_num)?)?;
-    let dist_obj_size = Dist::from(c_string_to_str(info.dist_obj_size)?)?;
+    let dist_html_size = &config.dist_html_size;
+    let dist_obj_num = &config.dist_obj_num;
+    let dist_obj_size = &config.dist_obj_size;
 
     // we'll have at least as many objects as the original ones
     let initial_obj_num = objects.len();
 
     // Sample target number of objects (count)
-    let mut target_obj_num = match sample_ge(&dist_obj_num, initial_obj_num) {
+    let mut target_obj_num = match sample_ge(dist_obj_num, initial_obj_num) {
         Ok(c) => c,
         Err(e) => {
-            eprint!("libalpaca: could not sample object number ({}), leaving unchanged ({})\n", e, initial_obj_num);
+            warnings.push(AlpacaError::Sampling(format!("no object number was found, left at {} ({})", initial_obj_num, e)));
             initial_obj_num
         }
     };
 
     // sample target html size
-    let content = dom::serialize_html(&document);
-    let min_html_size = content.len()
+    let min_html_size = html_size
         + 7                                         // for the comment characters
         + 23 * initial_obj_num                       // for ?alpaca-padding=...
         + 94 * (target_obj_num - initial_obj_num);   // for the fake images
     let target_html_size;
 
     // find object sizes
-    if info.use_total_obj_size == 0 {
+    if !config.use_total_obj_size {
         // Sample each object size from dist_obj_size.
         //
-        target_html_size = sample_ge(&dist_html_size, min_html_size)?;
+        target_html_size = sample_ge(dist_html_size, min_html_size)?;
 
         // To more closely match the actual obj_size distribution, we'll sample values for all objects,
         // And then we'll use the largest to pad existing objects and the smallest for padding objects.
-        let mut target_obj_sizes: Vec<usize> = sample_ge_many(&dist_obj_size, 1, target_obj_num)?;
+        let mut target_obj_sizes: Vec<usize> = sample_ge_many(dist_obj_size, 1, target_obj_num)?;
         target_obj_sizes.sort_unstable();       // ascending
 
         // Pad existing objects
         for obj in &mut *objects {
-            let needed_size = obj.content.len() + pad::min_obj_padding(&obj);
+            let needed_size = obj.size + pad::min_obj_padding(obj);
 
             // Take the largest size, if not enough draw a new one with this specific needed_size
             obj.target_size = if target_obj_sizes[target_obj_sizes.len()-1] >= needed_size {
-                Some(target_obj_sizes.pop().unwrap())
+                target_obj_sizes.pop()
             } else {
-                match sample_ge(&dist_obj_size, needed_size) {
+                match sample_ge(dist_obj_size, needed_size) {
                     Ok(size) => Some(size),
                     Err(e) => {
-                        eprint!("libalpaca: warning: no padding was found for {} ({})\n", obj.uri, e);
+                        warnings.push(unpadded(&obj.uri, e));
                         None
                     },
                 }
-            };
+            }.map(|size| size.next_multiple_of(pad::obj_size_step(obj)));
         }
 
         // create padding objects, using the smallest of the sizes
-        for i in 0..target_obj_num - initial_obj_num {
-            objects.push(Object::fake_image(target_obj_sizes[i]));
+        for &size in target_obj_sizes.iter().take(target_obj_num - initial_obj_num) {
+            objects.push(Object::fake_image(size));
         }
 
     } else {
         // Sample the __total__ object size from dist_obj_size.
 
         // min size of all objects
-        let min_obj_size = objects.into_iter().map(
-            |obj| obj.content.len() + pad::min_obj_padding(obj)
+        let min_obj_size = objects.iter().map(
+            |obj| obj.size + pad::min_obj_padding(obj)
         ).sum();
         let target_obj_size;
 
         // sample html/obj sizes, either together or separately
         if dist_obj_size.name == "Joint" {
-            match sample_pair_ge(&dist_html_size, (min_html_size, min_obj_size))? {
-                (a, b) => {
-                    target_html_size = a;
-                    target_obj_size = b;
-                }
-            }
+            let (a, b) = sample_pair_ge(dist_html_size, (min_html_size, min_obj_size))?;
+            target_html_size = a;
+            target_obj_size = b;
         } else {
-            target_html_size = sample_ge(&dist_html_size, min_html_size)?;
-            target_obj_size  = sample_ge(&dist_obj_size,  min_obj_size )?;
+            target_html_size = sample_ge(dist_html_size, min_html_size)?;
+            target_obj_size  = sample_ge(dist_obj_size,  min_obj_size )?;
         }
 
         // create empty fake images
@@ -231,7 +485,7 @@ fn morph_probabilistic (
         let mut to_split  = target_obj_size - min_obj_size;
         for (pos, obj) in objects.iter_mut().enumerate() {
             let pad = to_split / (target_obj_num - pos);
-            obj.target_size = Some(obj.content.len() + pad::min_obj_padding(obj) + pad);
+            obj.target_size = Some((obj.size + pad::min_obj_padding(obj) + pad).next_multiple_of(pad::obj_size_step(obj)));
             to_split -= pad;
         }
     }
@@ -240,10 +494,9 @@ fn morph_probabilistic (
 }
 
 fn morph_deterministic(
-    document: &NodeRef,
     objects: &mut Vec<Object>,
-    info: &MorphInfo,
-) -> Result<usize, String> {
+    config: &DeterministicConfig,
+) -> Result<(), AlpacaError> {
     // we'll have at least as many objects as the original ones
     let initial_obj_no = objects.len();
 
@@ -251,70 +504,64 @@ fn morph_deterministic(
     // objects. Count is a multiple of "obj_num" and bigger than "min_count".
     // Target size for each objects is a multiple of "obj_size" and bigger
     // than the object's  original size.
-    let target_count = get_multiple(info.obj_num, initial_obj_no);
+    let target_count = get_multiple(config.obj_num, initial_obj_no);
 
-    for i in 0..objects.len() {
-        let min_size = objects[i].content.len()
-            + match objects[i].kind { ObjectKind::CSS | ObjectKind::JS => 4, _ => 0 };
+    for obj in objects.iter_mut() {
+        let min_size = obj.size + pad::min_obj_padding(obj);
 
-        let obj_target_size = get_multiple(info.obj_size, min_size);
-        objects[i].target_size = Some(obj_target_size);
+        obj.target_size = Some(get_multiple(step_multiple(config.obj_size, pad::obj_size_step(obj)), min_size));
     }
 
     let fake_objects_count = target_count - initial_obj_no; // The number of fake objects.
 
     // To get the target size of each fake object, sample uniformly a multiple
     // of "obj_size" which is smaller than "max_obj_size".
-    let fake_objects_sizes = get_multiples_in_range(info.obj_size, info.max_obj_size, fake_objects_count)?;
+    let fake_objects_sizes = get_multiples_in_range(config.obj_size, config.max_obj_size, fake_objects_count)?;
 
     // Add the fake objects to the vector.
-    for i in 0..fake_objects_count {
-        objects.push(Object::fake_image(fake_objects_sizes[i]));
+    for size in fake_objects_sizes {
+        objects.push(Object::fake_image(size));
     }
+    Ok(())
+}
 
-    // find target size,a multiple of "obj_size".
-    let content = dom::serialize_html(&document);
-    let html_min_size = content.len() + 7; // Plus 7 because of the comment characters.
-    Ok(get_multiple(info.obj_size, html_min_size))
+// The smallest multiple of `obj_size` that is also a multiple of `step`.
+fn step_multiple(obj_size: usize, step: usize) -> usize {
+    if obj_size.is_multiple_of(step) { obj_size } else { obj_size * step }
 }
 
 /// Inserts the ALPaCA GET parameters to the html objects, and adds the fake objects to the html.
-fn insert_objects_refs(document: &NodeRef, objects: &[Object], n: usize) -> Result<(), String> {
+fn insert_objects_refs(document: &NodeRef, objects: &[Object], n: usize) -> Result<(), AlpacaError> {
     let init_obj = &objects[0..n]; // Slice which contains initial objects
     let padding_obj = &objects[n..]; // Slice which contains ALPaCA objects
 
-    for object in init_obj {
-        // ignore objects without target size
-        if !object.target_size.is_none() {
-            append_ref(&object);
-        }
+    // ignore objects without target size, rewrite the rest from last to
+    // first uri in each node (see Location::start)
+    let mut refs: Vec<&Object> = init_obj.iter().filter(|obj| obj.target_size.is_some()).collect();
+    refs.sort_by_key(|obj| std::cmp::Reverse(obj.location.start()));
+
+    for object in refs {
+        append_ref(object);
     }
 
-    add_padding_objects(&document, padding_obj);
+    add_padding_objects(document, padding_obj);
 
     Ok(())
 }
 
 /// Appends the ALPaCA GET parameter to an html element
 fn append_ref(object: &Object) {
-    // Construct the link with the appended new parameter
-    let mut new_link = String::from("alpaca-padding=");
-    new_link.push_str(&(object.target_size.unwrap().to_string())); // Append the target size
-
-    let node = object.node.as_ref().unwrap();
-    let attr = match node.as_element().unwrap().name.local.to_lowercase().as_ref() {
-        "img" | "script" => "src",
-        "link" => "href",
-        _ => panic!("shouldn't happen"),
+    let node = match object.node {
+        Some(ref node) => node,
+        None => return,
     };
 
-    // Check if there is already a GET parameter in the file path
-    let prefix = if object.uri.contains("?") { '&' } else { '?' };
-
-    new_link.insert(0, prefix);
-    new_link.insert_str(0, &object.uri);
+    dom::node_set_uri(node, &object.location, uri::padded_uri(&object.uri, object.target_size.unwrap()));
+}
 
-    dom::node_set_attribute(node, attr, new_link);
+// The warning of an object left unpadded, as no target size could be sampled.
+fn unpadded(uri: &str, e: AlpacaError) -> AlpacaError {
+    AlpacaError::Sampling(format!("no padding was found for {} ({})", uri, e))
 }
 
 /// Adds the fake ALPaCA objects in the end of the html body
@@ -328,33 +575,10 @@ fn add_padding_objects(document: &NodeRef, objects: &[Object]) {
         None => document,
     };
 
-    let mut i = 1;
-    for object in objects {
+    for (i, object) in objects.iter().enumerate() {
         let elem = dom::create_element("img");
-        dom::node_set_attribute(&elem, "src", format!("/__alpaca_fake_image.png?alpaca-padding={}&i={}", object.target_size.unwrap(), i));
+        dom::node_set_attribute(&elem, "src", format!("/__alpaca_fake_image.png?alpaca-padding={}&i={}", object.target_size.unwrap(), i + 1));
         dom::node_set_attribute(&elem, "style", String::from("visibility:hidden"));
         node.append(elem);
-        i += 1;
     }
 }
-
-// Builds the returned html, stores its size in html_size and returns a
-// 'forgotten' unsafe pointer to the html, for returning to C
-//
-fn document_to_c(document: &NodeRef, info: &mut MorphInfo) -> u8 {
-    let content = dom::serialize_html(document);
-    return content_to_c(content, info);
-}
-
-fn content_to_c(content: Vec<u8>, info: &mut MorphInfo) -> u8 {
-    info.size = content.len();
-
-    let mut buf = content.into_boxed_slice();
-    info.content = buf.as_mut_ptr();
-    std::mem::forget(buf);
-    1
-}
-
-fn c_string_to_str<'a>(s: *const u8) -> Result<&'a str, String> {
-    return stringify_error(unsafe { CStr::from_ptr(s as *const i8) }.to_str());
-}
\ No newline at end of file
@@ -1,81 +1,361 @@
 //! Contains padding functions for different resource types.
-use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
-use std::iter::Extend;
+use std::io::{self, Read};
+use std::iter::{self, Extend};
+use std::ops::Deref;
+use std::path::Path;
+use std::str;
 
 use dom::{ObjectKind,Object};
+use config::HtmlPadding;
+use inplace;
+use error::AlpacaError;
+use image::{self, ImageFormat, MIN_IMAGE_PADDING};
+use font::{self, MIN_FONT_PADDING};
+use source::{self, PaddingSource};
 
-static CSS_COMMENT_START: &'static str = "/*";
+static CSS_COMMENT_START: &str = "/*";
 const CSS_COMMENT_START_SIZE: usize = 2;
-static CSS_COMMENT_END: &'static str = "*/";
+static CSS_COMMENT_END: &str = "*/";
 const CSS_COMMENT_END_SIZE: usize = 2;
-static HTML_COMMENT_START: &'static str = "<!--";
+static HTML_COMMENT_START: &str = "<!--";
 const HTML_COMMENT_START_SIZE: usize = 4;
-static HTML_COMMENT_END: &'static str = "-->";
+static HTML_COMMENT_END: &str = "-->";
 const HTML_COMMENT_END_SIZE: usize = 3;
 
+// A wasm custom section: id 0, a 5-byte (non-minimal) LEB128 size, then its name.
+const WASM_SECTION_NAME: &[u8] = b"alpaca";
+const WASM_SECTION_HEADER: usize = 1 + 5 + 1 + 6;
+
+// PDF lines should not be longer than 255 bytes.
+const PDF_LINE: usize = 255;
+
+/// The minimum padding of an html page.
+pub const MIN_HTML_PADDING: usize = HTML_COMMENT_START_SIZE + HTML_COMMENT_END_SIZE;
+
+static HIDDEN_DIV_START: &str = "<div hidden>";
+static HIDDEN_DIV_END: &str = "</div>";
+// ` data-<name>=""`, with a random name
+const DATA_ATTR_NAME_SIZE: usize = 8;
+const DATA_ATTR_SIZE: usize = 6 + DATA_ATTR_NAME_SIZE + 3;
+// The maximum number of comments of split padding.
+const MAX_SPLIT_COMMENTS: usize = 8;
+
 /// Pads an html to its target size.
-pub fn get_html_padding(content: &mut Vec<u8>, target_size: usize) {
-    let current_size = content.len() + HTML_COMMENT_START_SIZE + HTML_COMMENT_END_SIZE;
+pub fn get_html_padding(content: &mut Vec<u8>, target_size: usize, source: &dyn PaddingSource) -> Result<(), AlpacaError> {
+    let current_size = content.len() + MIN_HTML_PADDING;
     if target_size < current_size {
-        eprint!("alpaca: warning: asking for negative html pad\n");
-        return;
+        return Err(AlpacaError::PaddingUnderflow { size: current_size, target_size });
     }
-    let pad_len = target_size - current_size;
+    content.extend(html_padding(target_size - content.len(), source)?);
+    Ok(())
+}
+
+/// Returns `pad_len` bytes of padding for an html page (a comment).
+pub fn html_padding(pad_len: usize, source: &dyn PaddingSource) -> Result<Vec<u8>, AlpacaError> {
+    if pad_len < MIN_HTML_PADDING {
+        return Err(AlpacaError::PaddingUnderflow { size: MIN_HTML_PADDING, target_size: pad_len });
+    }
+    Ok(html_comment(pad_len, source))
+}
 
+// A comment of `pad_len` (at least MIN_HTML_PADDING) bytes.
+fn html_comment(pad_len: usize, source: &dyn PaddingSource) -> Vec<u8> {
     let mut pad = Vec::from(HTML_COMMENT_START);
-    add_random_chars(&mut pad, pad_len);
+    add_random_chars(&mut pad, pad_len - MIN_HTML_PADDING, source);
     pad.extend(Vec::from(HTML_COMMENT_END));
-    content.extend(pad);
+    pad
+}
+
+/// The minimum padding of an html page, with the padding placed as `placement`.
+pub fn min_html_padding(placement: HtmlPadding) -> usize {
+    match placement {
+        HtmlPadding::Div => HIDDEN_DIV_START.len() + HIDDEN_DIV_END.len(),
+        HtmlPadding::Attribute => DATA_ATTR_SIZE,
+        _ => MIN_HTML_PADDING,
+    }
+}
+
+/// Inserts `pad_len` bytes of padding in `html`, placed as `placement`.
+/// Pages lacking the elements of the placement are padded at their end.
+pub fn place_html_padding(html: &str, pad_len: usize, placement: HtmlPadding, source: &dyn PaddingSource) -> Result<String, AlpacaError> {
+    if pad_len < min_html_padding(placement) {
+        return Err(AlpacaError::PaddingUnderflow { size: min_html_padding(placement), target_size: pad_len });
+    }
+    let (tags, end_tags) = inplace::tag_positions(html);
+    let body_end = end_tags.iter().rev().find(|t| t.0 == "body").map(|t| t.1);
+
+    let mut insertions = match placement {
+        HtmlPadding::Split => split_html_padding(html, &tags, &end_tags, pad_len, source),
+        _ => {
+            let (pos, pad) = match placement {
+                HtmlPadding::Head => (end_tags.iter().find(|t| t.0 == "head").map(|t| t.1), html_comment(pad_len, source)),
+                HtmlPadding::Body => (body_end, html_comment(pad_len, source)),
+                HtmlPadding::Div => {
+                    let mut div = Vec::from(HIDDEN_DIV_START);
+                    add_random_chars(&mut div, pad_len - min_html_padding(placement), source);
+                    div.extend_from_slice(HIDDEN_DIV_END.as_bytes());
+                    (body_end, div)
+                },
+                HtmlPadding::Attribute => {
+                    let mut attr = Vec::from(" data-");
+                    attr.extend((0..DATA_ATTR_NAME_SIZE).map(|_| b'a' + source::gen_index(source, 26) as u8));
+                    attr.extend_from_slice(b"=\"");
+                    add_random_chars(&mut attr, pad_len - DATA_ATTR_SIZE, source);
+                    attr.push(b'"');
+                    (tags.iter().find(|t| t.0 == "body").map(|t| t.1 + "<body".len()), attr)
+                },
+                _ => (None, Vec::new()),
+            };
+            match pos {
+                Some(pos) => vec![(pos, pad)],
+                None => vec![(html.len(), html_comment(pad_len, source))],
+            }
+        },
+    };
+    insertions.sort_by_key(|i| i.0);
+
+    let mut padded = String::with_capacity(html.len() + pad_len);
+    let mut start = 0;
+    for (pos, pad) in insertions {
+        padded.push_str(&html[start..pos]);
+        padded.push_str(str::from_utf8(&pad)?);
+        start = pos;
+    }
+    padded.push_str(&html[start..]);
+    Ok(padded)
+}
+
+// Comments of random sizes, before random tags (or at the end).
+fn split_html_padding(html: &str, tags: &[(String, usize)], end_tags: &[(String, usize)], pad_len: usize, source: &dyn PaddingSource) -> Vec<(usize, Vec<u8>)> {
+    let mut positions: Vec<usize> = tags.iter().chain(end_tags).map(|t| t.1).collect();
+    positions.push(html.len());
+
+    let count = 1 + source::gen_index(source, MAX_SPLIT_COMMENTS.min(pad_len / MIN_HTML_PADDING));
+    let mut cuts: Vec<usize> = (1..count).map(|_| source::gen_index(source, pad_len - count * MIN_HTML_PADDING + 1)).collect();
+    cuts.sort_unstable();
+    cuts.insert(0, 0);
+    cuts.push(pad_len - count * MIN_HTML_PADDING);
+
+    cuts.windows(2).map(|w| {
+        let pos = positions[source::gen_index(source, positions.len())];
+        (pos, html_comment(MIN_HTML_PADDING + w[1] - w[0], source))
+    }).collect()
 }
 
 pub fn min_obj_padding(obj: &Object) -> usize {
-    // CSS/JS padding needs to be at least 4.
-    match obj.kind {
-        ObjectKind::CSS | ObjectKind::JS => 4,
+    min_padding(&obj.kind)
+}
+
+pub fn obj_size_step(obj: &Object) -> usize {
+    size_step(&obj.kind, obj.path.as_deref(), &obj.uri)
+}
+
+/// The sizes an object can be padded to are multiples of this step: images
+/// whose format needs one (WebP) are recognized by the extension of their
+/// file, or else of their `uri`.
+pub fn size_step(kind: &ObjectKind, path: Option<&Path>, uri: &str) -> usize {
+    if *kind != ObjectKind::IMG {
+        return 1;
+    }
+    let path = path.unwrap_or_else(|| Path::new(uri.split(['?', '#']).next().unwrap_or_default()));
+    ImageFormat::from_path(path).map_or(1, |format| format.size_step())
+}
+
+/// The minimum padding that can be added to an object of the given kind.
+pub fn min_padding(kind: &ObjectKind) -> usize {
+    // CSS/JS padding needs to be at least 4, images and fonts need room for
+    // a chunk of their container (see pad_object_content).
+    match *kind {
+        ObjectKind::CSS | ObjectKind::JS => CSS_COMMENT_START_SIZE + CSS_COMMENT_END_SIZE,
+        ObjectKind::SVG => MIN_HTML_PADDING,
+        ObjectKind::IMG => MIN_IMAGE_PADDING,
+        ObjectKind::Font => MIN_FONT_PADDING,
+        ObjectKind::Wasm => WASM_SECTION_HEADER,
         _ => 0
     }
 }
 
 
 /// Pads an object to its target size.
-pub fn get_object_padding(kind: ObjectKind, size: usize, target_size: usize) -> Vec<u8> {
-    let pad_len = target_size - size;
-    let padding;
-    match kind {
-        ObjectKind::CSS | ObjectKind::JS => {
-            if size + 4 > target_size {
-                // Consider the 4 additional comment-bytes.
-                return Vec::new();
+pub fn get_object_padding(kind: ObjectKind, size: usize, target_size: usize, source: &dyn PaddingSource) -> Result<Vec<u8>, AlpacaError> {
+    let mut reader = PadReader::new(kind, size, target_size, source)?;
+    let mut pad = vec![0; reader.remaining()];
+    reader.fill(&mut pad);
+    Ok(pad)
+}
+
+/// The size of the chunks of `PadReader::chunks`, when none is given.
+pub const DEFAULT_CHUNK_SIZE: usize = 8192;
+
+// The content between the fixed parts of a padding.
+enum Fill {
+    Text,
+    Bytes,
+    Whitespace,     // allowed after a JSON value
+}
+
+/// Generates the padding of an object (the one of `get_object_padding`)
+/// in chunks of any size, without holding it whole in memory. `S` is a
+/// reference to the padding source, eg `&dyn PaddingSource` or an `Arc`.
+pub struct PadReader<S> {
+    source: S,
+    fill: Fill,
+    // the padding is made of `records` parts of equal size (PDF comment
+    // lines, a single part otherwise), each with a header and a trailer
+    header: Vec<u8>,
+    trailer: &'static [u8],
+    records: usize,
+    record: usize,
+    pad_len: usize,
+    pos: usize,
+}
+
+impl<S> PadReader<S> where S: Deref, S::Target: PaddingSource {
+    /// A reader of the padding of an object of `kind`, from `size` to `target_size`.
+    pub fn new(kind: ObjectKind, size: usize, target_size: usize, source: S) -> Result<PadReader<S>, AlpacaError> {
+        if size + min_padding(&kind) > target_size {
+            // Consider the additional comment-bytes.
+            return Err(AlpacaError::PaddingUnderflow { size: size + min_padding(&kind), target_size });
+        }
+        let pad_len = target_size - size;
+        let (fill, header, trailer, records) = match kind {
+            ObjectKind::CSS | ObjectKind::JS => (Fill::Text, Vec::from(CSS_COMMENT_START), CSS_COMMENT_END.as_bytes(), 1),
+            // an XML comment after the root element
+            ObjectKind::SVG => (Fill::Text, Vec::from(HTML_COMMENT_START), HTML_COMMENT_END.as_bytes(), 1),
+            ObjectKind::JSON => (Fill::Whitespace, Vec::new(), &b""[..], 1),
+            ObjectKind::Wasm => (Fill::Bytes, wasm_section_header(pad_len)?, &b""[..], 1),
+            // comment lines, or a single end of line
+            ObjectKind::PDF if pad_len == 1 => (Fill::Text, Vec::new(), &b"\n"[..], 1),
+            ObjectKind::PDF => (Fill::Text, Vec::from("%"), &b"\n"[..], pad_len.div_ceil(PDF_LINE)),
+            // raw bytes, that images and fonts only get inside their
+            // container from pad_object_content
+            _ => (Fill::Bytes, Vec::new(), &b""[..], 1),
+        };
+        Ok(PadReader { source, fill, header, trailer, records, record: 0, pad_len, pos: 0 })
+    }
+
+    /// The number of bytes left to read.
+    pub fn remaining(&self) -> usize {
+        self.pad_len - self.pos
+    }
+
+    /// Fills `buf` with the next bytes of the padding. Returns the number of
+    /// bytes written, less than `buf.len()` only at the end of the padding.
+    pub fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let mut written = 0;
+        while written < buf.len() && self.pos < self.pad_len {
+            let start = self.pad_len * self.record / self.records;
+            let end = self.pad_len * (self.record + 1) / self.records;
+            let body_end = end - start - self.trailer.len();
+            let offset = self.pos - start;
+
+            let out = &mut buf[written..];
+            let n = if offset < self.header.len() {
+                copy_part(&self.header[offset..], out)
+            } else if offset < body_end {
+                let len = (body_end - offset).min(out.len());
+                let out = &mut out[..len];
+                match self.fill {
+                    Fill::Text => self.source.fill_text(out),
+                    Fill::Bytes => self.source.fill_bytes(out),
+                    Fill::Whitespace => {
+                        self.source.fill_bytes(out);
+                        for b in out.iter_mut() {
+                            *b = b" \t\n\r"[(*b & 3) as usize];
+                        }
+                    },
+                }
+                out.len()
+            } else {
+                copy_part(&self.trailer[offset - body_end..], out)
+            };
+
+            written += n;
+            self.pos += n;
+            if self.pos == end {
+                self.record += 1;
             }
-            padding = get_css_padding(pad_len);
         }
-        _ => padding = get_binary_padding(pad_len),
-    };
+        written
+    }
+
+    /// The rest of the padding, in chunks of `chunk_size` bytes (the last
+    /// one may be shorter), or of `DEFAULT_CHUNK_SIZE` bytes if it is 0.
+    pub fn chunks(mut self, chunk_size: usize) -> impl Iterator<Item = Vec<u8>> {
+        let chunk_size = if chunk_size == 0 { DEFAULT_CHUNK_SIZE } else { chunk_size };
+        iter::from_fn(move || {
+            let mut chunk = vec![0; chunk_size.min(self.remaining())];
+            if chunk.is_empty() {
+                return None;
+            }
+            self.fill(&mut chunk);
+            Some(chunk)
+        })
+    }
+}
 
-    padding
+impl<S> Read for PadReader<S> where S: Deref, S::Target: PaddingSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.fill(buf))
+    }
 }
 
-fn get_css_padding(pad_len: usize) -> Vec<u8> {
-    let pad_len = pad_len - CSS_COMMENT_START_SIZE - CSS_COMMENT_END_SIZE;
-    let mut pad = Vec::from(CSS_COMMENT_START);
-    add_random_chars(&mut pad, pad_len);
-    pad.extend(Vec::from(CSS_COMMENT_END));
-    pad
+// Copies the start of `part` to `out`, returning its length.
+fn copy_part(part: &[u8], out: &mut [u8]) -> usize {
+    let n = part.len().min(out.len());
+    out[..n].copy_from_slice(&part[..n]);
+    n
 }
 
-fn add_random_chars(pad: &mut Vec<u8>, pad_len: usize) {
-    let mut rng = thread_rng();
-    for _ in 0..pad_len {
-        pad.push(rng.sample(Alphanumeric) as u8);
+/// Pads the whole object `content` to its target size. The padding of
+/// images, fonts and PDFs is inserted in their container, so that they
+/// remain valid; other objects are padded at their end, like with
+/// `get_object_padding`.
+pub fn pad_object_content(kind: ObjectKind, content: &[u8], target_size: usize, source: &dyn PaddingSource) -> Result<Vec<u8>, AlpacaError> {
+    match kind {
+        ObjectKind::IMG => return image::pad_image(content, target_size, source),
+        ObjectKind::Font => return font::pad_font(content, target_size, source),
+        _ => {},
     }
+    let padding = get_object_padding(kind, content.len(), target_size, source)?;
+    let padded = match kind {
+        ObjectKind::PDF => content.windows(9).rposition(|w| w == b"startxref").map(|pos| {
+            // comments before the startxref keyword, which keeps the offsets
+            let mut padded = content.to_vec();
+            padded.splice(pos..pos, padding.iter().cloned());
+            padded
+        }),
+        _ => None,
+    };
+    Ok(padded.unwrap_or_else(|| [content, &padding[..]].concat()))
 }
 
-fn get_binary_padding(pad_len: usize) -> Vec<u8> {
-    let mut rng = thread_rng();
-    let mut pad: Vec<u8> = Vec::with_capacity(pad_len);
-    for _ in 0..pad_len {
-        pad.push(rng.gen::<u8>());
+// The header of a custom section of `pad_len` bytes, which wasm modules can
+// have anywhere.
+fn wasm_section_header(pad_len: usize) -> Result<Vec<u8>, AlpacaError> {
+    let size = pad_len - WASM_SECTION_HEADER + 1 + WASM_SECTION_NAME.len();
+    if size > u32::MAX as usize {
+        return Err(AlpacaError::TargetTooLarge(pad_len));
     }
+    let mut header = vec![0];
+    for i in 0..5 {
+        let byte = ((size >> (7 * i)) & 0x7F) as u8;
+        header.push(if i < 4 { byte | 0x80 } else { byte });
+    }
+    header.push(WASM_SECTION_NAME.len() as u8);
+    header.extend_from_slice(WASM_SECTION_NAME);
+    Ok(header)
+}
+
+fn add_random_chars(pad: &mut Vec<u8>, pad_len: usize, source: &dyn PaddingSource) {
+    let start = pad.len();
+    pad.resize(start + pad_len, 0);
+    source.fill_text(&mut pad[start..]);
+}
+
+/// Returns `pad_len` bytes of binary padding from `source`.
+pub fn get_binary_padding(pad_len: usize, source: &dyn PaddingSource) -> Vec<u8> {
+    let mut pad = vec![0; pad_len];
+    source.fill_bytes(&mut pad);
     pad
 }
@@ -0,0 +1,139 @@
+//! Selects the morphing configuration of a request by its host and uri.
+use config::MorphConfig;
+
+/// A host or uri pattern. Patterns containing `*` (any sequence of
+/// characters) or `?` (any single character) must match the whole string,
+/// other patterns are prefixes (uris) or exact names (hosts).
+pub struct Pattern {
+    pattern: String,
+    glob: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        Pattern {
+            pattern: String::from(pattern),
+            glob: pattern.contains(['*', '?']),
+        }
+    }
+
+    /// The pattern, as given.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Number of non-wildcard characters, more specific patterns win.
+    fn specificity(&self) -> usize {
+        self.pattern.chars().filter(|&c| c != '*' && c != '?').count()
+    }
+
+    fn matches_uri(&self, uri: &str) -> bool {
+        if self.glob {
+            glob_match(self.pattern.as_bytes(), uri.as_bytes())
+        } else {
+            uri.starts_with(self.pattern.as_str())
+        }
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        let pattern = self.pattern.to_ascii_lowercase();
+        let host = host.to_ascii_lowercase();
+        if self.glob {
+            glob_match(pattern.as_bytes(), host.as_bytes())
+        } else {
+            pattern == host
+        }
+    }
+}
+
+/// A morphing configuration applied to the requests matching `host` and
+/// `path` (a missing pattern matches everything).
+pub struct Policy {
+    pub host: Option<Pattern>,
+    pub path: Option<Pattern>,
+    pub morph: MorphConfig,
+}
+
+impl Policy {
+    fn matches(&self, host: &str, uri: &str) -> bool {
+        self.host.as_ref().is_none_or(|p| p.matches_host(host))
+            && self.path.as_ref().is_none_or(|p| p.matches_uri(uri))
+    }
+
+    // Policies for exact host names win, then those for the most specific
+    // host glob, then those with the longest path.
+    fn specificity(&self) -> (bool, usize, usize) {
+        (
+            self.host.as_ref().is_some_and(|p| !p.glob),
+            self.host.as_ref().map_or(0, Pattern::specificity),
+            self.path.as_ref().map_or(0, Pattern::specificity),
+        )
+    }
+}
+
+/// A list of policies, the most specific one matching a request is used.
+#[derive(Default)]
+pub struct PolicyRouter {
+    policies: Vec<Policy>,
+}
+
+impl PolicyRouter {
+    pub fn new() -> PolicyRouter {
+        PolicyRouter { policies: Vec::new() }
+    }
+
+    pub fn add(&mut self, policy: Policy) {
+        self.policies.push(policy);
+    }
+
+    pub fn policies(&self) -> &[Policy] {
+        &self.policies
+    }
+
+    /// Returns the most specific policy matching the request, on ties the
+    /// one added first. `http_host` may contain a port, which is ignored.
+    pub fn select(&self, http_host: &str, uri: &str) -> Option<&Policy> {
+        let host = strip_port(http_host);
+        let mut best: Option<&Policy> = None;
+
+        for policy in self.policies.iter().filter(|p| p.matches(host, uri)) {
+            if best.is_none_or(|b| policy.specificity() > b.specificity()) {
+                best = Some(policy);
+            }
+        }
+        best
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    // keep IPv6 literals ([::1]:80) intact
+    match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[..pos],
+        _ => host,
+    }
+}
+
+// Matches text against a pattern with * and ? wildcards, backtracking only
+// to the last *.
+//
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;     // (pattern pos after *, text pos)
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,161 @@
+//! Resolves the objects referenced by a page to their size and kind.
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use cache::ObjectCache;
+use css;
+use dom;
+use dom::ObjectKind;
+use morphing::RequestContext;
+use error::AlpacaError;
+
+/// An object referenced by a page, as found by an `ObjectResolver`.
+#[derive(Clone)]
+pub struct ResolvedObject {
+    /// Size of the object, as it is going to be served
+    pub size: usize,
+    /// Kind of the object, None to keep the one implied by the html element
+    pub kind: Option<ObjectKind>,
+    /// File containing the object, if any
+    pub path: Option<PathBuf>,
+    /// Number of object references of a stylesheet (see `css::scan_refs`),
+    /// which grows by up to `css::rewrite_overhead` when morphed
+    pub css_refs: usize,
+}
+
+/// Finds the objects referenced by a page. Implementations are shared by all
+/// requests, possibly from several threads.
+pub trait ObjectResolver: Send + Sync {
+    /// Resolves `reference`, the path of an object referenced by the page
+    /// `ctx.uri` (absolute and percent-decoded, without query or fragment).
+    /// Returns `Ok(None)` for objects that cannot be morphed, which are
+    /// silently left unchanged; errors are logged and the object is also
+    /// left unchanged. References to other hosts never reach the resolver.
+    fn resolve(&self, ctx: &RequestContext, reference: &str) -> Result<Option<ResolvedObject>, AlpacaError>;
+}
+
+/// Maps the uris starting with `prefix` to files under `path`, like the
+/// `root` (the whole uri is appended to `path`) and `alias` (the prefix is
+/// replaced by `path`) directives of nginx and Apache. `$http_host` in
+/// `path` is replaced by the Host of the request.
+#[derive(Clone, Debug)]
+pub struct DocumentRoot {
+    pub prefix: String,
+    pub path: String,
+    pub alias: bool,
+}
+
+/// The default resolver, mapping uris to files under the document root.
+/// The root of an object is that of the longest matching prefix in the
+/// table of roots, if any, otherwise the one of the request (`ctx.root` and
+/// `ctx.alias`). Files outside the root (eg through symlinks) are refused.
+/// Only the metadata of the files is read (and the content of stylesheets, to
+/// count their references), and cached in `ObjectCache::global`; their kind
+/// is taken from their extension (see `dom::path_object_kind`).
+#[derive(Default)]
+pub struct FsResolver {
+    roots: Vec<DocumentRoot>,
+}
+
+impl FsResolver {
+    /// A resolver using only the root of each request.
+    pub fn new() -> FsResolver {
+        FsResolver::default()
+    }
+
+    /// A resolver using the given table of roots.
+    pub fn with_roots(roots: Vec<DocumentRoot>) -> FsResolver {
+        FsResolver { roots }
+    }
+
+    pub fn add_root(&mut self, root: DocumentRoot) {
+        self.roots.push(root);
+    }
+
+    pub fn roots(&self) -> &[DocumentRoot] {
+        &self.roots
+    }
+
+    // The root (with $http_host replaced) and the file path of an object,
+    // None if it is in another server or location.
+    fn fs_path(&self, ctx: &RequestContext, reference: &str) -> Option<(String, String)> {
+        // the table is matched against the normalized absolute uri
+        let uri = dom::uri_to_abs_fs_path("", reference, ctx.uri, 0)?;
+        let table_root = self.roots.iter()
+            .filter(|root| uri.starts_with(root.prefix.as_str()))
+            .max_by_key(|root| root.prefix.len());
+
+        match table_root {
+            Some(root) => {
+                let path = root.path.replace("$http_host", ctx.http_host);
+                let fs_path = if root.alias {
+                    dom::uri_to_abs_fs_path(&path, &uri, &root.prefix, root.prefix.len())?
+                } else {
+                    dom::uri_to_abs_fs_path(&path, &uri, "/", 0)?
+                };
+                Some((path, fs_path))
+            },
+            None => {
+                let path = ctx.root.replace("$http_host", ctx.http_host);
+                let fs_path = dom::uri_to_abs_fs_path(&path, reference, ctx.uri, ctx.alias)?;
+                Some((path, fs_path))
+            },
+        }
+    }
+}
+
+impl ObjectResolver for FsResolver {
+    fn resolve(&self, ctx: &RequestContext, reference: &str) -> Result<Option<ResolvedObject>, AlpacaError> {
+        // the Host is chosen by the client, it must not move the root elsewhere
+        let templated = ctx.root.contains("$http_host") || self.roots.iter().any(|root| root.path.contains("$http_host"));
+        if templated && !is_safe_host(ctx.http_host) {
+            return Err(AlpacaError::InvalidArgument(format!("Host \"{}\" cannot be used in the document root", ctx.http_host.escape_default())));
+        }
+
+        let (root, path) = match self.fs_path(ctx, reference) {
+            Some(paths) => paths,
+            None => return Ok(None),
+        };
+        let path = PathBuf::from(path);
+        // the cache is shared by all roots, it is keyed by the confined path
+        let canonical = confine(Path::new(&root), &path)?;
+        let cache = ObjectCache::global();
+        if let Some(object) = cache.get(&canonical) {
+            return Ok(Some(object));
+        }
+
+        let meta = match fs::metadata(&canonical) {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Err(AlpacaError::Io(path.display().to_string(), io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"))),
+            Err(e) => return Err(AlpacaError::Io(path.display().to_string(), e)),
+        };
+
+        let kind = dom::path_object_kind(&canonical);
+        let css_refs = if kind == Some(ObjectKind::CSS) {
+            let content = fs::read(&canonical).map_err(|e| AlpacaError::Io(path.display().to_string(), e))?;
+            css::scan_refs(&String::from_utf8_lossy(&content)).len()
+        } else {
+            0
+        };
+        let object = ResolvedObject { size: meta.len() as usize, kind, path: Some(canonical.clone()), css_refs };
+        cache.insert(&canonical, &object, &meta);
+        Ok(Some(object))
+    }
+}
+
+/// Canonicalizes `path` (following all symlinks), which must then be
+/// located under the (also canonicalized) `root`.
+pub fn confine(root: &Path, path: &Path) -> Result<PathBuf, AlpacaError> {
+    let root = fs::canonicalize(root).map_err(|e| AlpacaError::Io(root.display().to_string(), e))?;
+    let canonical = fs::canonicalize(path).map_err(|e| AlpacaError::Io(path.display().to_string(), e))?;
+
+    if !canonical.starts_with(&root) {
+        let e = io::Error::new(io::ErrorKind::PermissionDenied, "outside the document root");
+        return Err(AlpacaError::Io(path.display().to_string(), e));
+    }
+    Ok(canonical)
+}
+
+// A Host that is a single path component.
+fn is_safe_host(host: &str) -> bool {
+    !host.is_empty() && host != "." && host != ".." && !host.contains(['/', '\\', '\0'])
+}
@@ -0,0 +1,131 @@
+//! Generators of the content of paddings. Paddings are either binary (eg in
+//! images) or text, which is placed in comments, attribute values and
+//! comment lines: text padding is printable ASCII without `<>&"'*-` or line
+//! breaks, so that it cannot end them.
+use std::sync::Mutex;
+use rand::{thread_rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
+
+const ALPHANUMERIC: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+// Tokens of html and css, with their separators, for MimicSource.
+const MIMIC_WORDS: &[&str] = &[
+    "div", "span", "class", "href", "style", "section", "header", "footer", "nav", "main", "item",
+    "content", "container", "wrapper", "button", "title", "link", "image", "list", "text", "menu",
+    "color", "margin", "padding", "width", "height", "display", "flex", "font", "border",
+    "background", "position", "relative", "absolute", "none", "auto", "inherit", "block", "solid",
+    "center", "left", "right", "top", "bottom", "bold", "normal", "hidden", "visible", "data",
+];
+const MIMIC_SEPARATORS: &[&str] = &[" ", " ", " ", ": ", "; ", " { ", " } ", "=", ".", ", ", "_", "#"];
+const MIMIC_UNITS: &[&str] = &["px", "em", "rem", "%", ""];
+
+/// A generator of padding content. Sources are shared by all requests, so
+/// they must be thread-safe.
+pub trait PaddingSource: Send + Sync {
+    /// Fills `out` with binary padding.
+    fn fill_bytes(&self, out: &mut [u8]);
+
+    /// Fills `out` with text padding (see the module documentation).
+    fn fill_text(&self, out: &mut [u8]);
+}
+
+/// The default source: binary padding from the thread's CSPRNG, filled in
+/// bulk, and alphanumeric text.
+#[derive(Default)]
+pub struct CsprngSource;
+
+impl PaddingSource for CsprngSource {
+    fn fill_bytes(&self, out: &mut [u8]) {
+        thread_rng().fill_bytes(out);
+    }
+
+    fn fill_text(&self, out: &mut [u8]) {
+        alphanumeric(&mut thread_rng(), out);
+    }
+}
+
+/// Alphanumeric padding, for both binary and text paddings.
+#[derive(Default)]
+pub struct AlphanumericSource;
+
+impl PaddingSource for AlphanumericSource {
+    fn fill_bytes(&self, out: &mut [u8]) {
+        alphanumeric(&mut thread_rng(), out);
+    }
+
+    fn fill_text(&self, out: &mut [u8]) {
+        alphanumeric(&mut thread_rng(), out);
+    }
+}
+
+/// Text padding made of html and css tokens (names, properties, numbers
+/// and their separators), which compresses and looks like the markup around
+/// it rather than like random data. Binary padding is from the CSPRNG.
+#[derive(Default)]
+pub struct MimicSource;
+
+impl PaddingSource for MimicSource {
+    fn fill_bytes(&self, out: &mut [u8]) {
+        thread_rng().fill_bytes(out);
+    }
+
+    fn fill_text(&self, out: &mut [u8]) {
+        mimic(&mut thread_rng(), out);
+    }
+}
+
+/// A deterministic source, seeded once: the same sequence of paddings is
+/// generated for the same seed. Like `CsprngSource` otherwise, it is meant
+/// for reproducible tests, not for production.
+pub struct SeededSource {
+    rng: Mutex<StdRng>,
+}
+
+impl SeededSource {
+    pub fn new(seed: u64) -> SeededSource {
+        SeededSource { rng: Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+}
+
+impl PaddingSource for SeededSource {
+    fn fill_bytes(&self, out: &mut [u8]) {
+        self.rng.lock().unwrap_or_else(|e| e.into_inner()).fill_bytes(out);
+    }
+
+    fn fill_text(&self, out: &mut [u8]) {
+        alphanumeric(&mut *self.rng.lock().unwrap_or_else(|e| e.into_inner()), out);
+    }
+}
+
+/// A random index below `n` (at least 1), from the binary padding of `source`.
+pub fn gen_index(source: &dyn PaddingSource, n: usize) -> usize {
+    let mut bytes = [0; 8];
+    source.fill_bytes(&mut bytes);
+    (u64::from_le_bytes(bytes) % n as u64) as usize
+}
+
+// Random alphanumeric bytes, drawn in bulk.
+fn alphanumeric<R: RngCore + ?Sized>(rng: &mut R, out: &mut [u8]) {
+    rng.fill_bytes(out);
+    for b in out.iter_mut() {
+        // 62 of the 64 values of the low bits are used, the rest redrawn
+        while *b & 63 >= 62 {
+            *b = rng.next_u32() as u8;
+        }
+        *b = ALPHANUMERIC[(*b & 63) as usize];
+    }
+}
+
+// Tokens and separators, cut at the length of `out`.
+fn mimic<R: RngCore + ?Sized>(rng: &mut R, out: &mut [u8]) {
+    let mut text = String::with_capacity(out.len() + 16);
+    while text.len() < out.len() {
+        let r = rng.next_u32() as usize;
+        match r % 8 {
+            0 => text.push_str(&format!("{}{}", r / 8 % 1000, MIMIC_UNITS[r / 8000 % MIMIC_UNITS.len()])),
+            _ => text.push_str(MIMIC_WORDS[r / 8 % MIMIC_WORDS.len()]),
+        }
+        text.push_str(MIMIC_SEPARATORS[rng.next_u32() as usize % MIMIC_SEPARATORS.len()]);
+    }
+    out.copy_from_slice(&text.as_bytes()[..out.len()]);
+}
@@ -0,0 +1,48 @@
+//! Resolution and rewriting of the uris referencing objects.
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use url::Url;
+
+// Characters of a decoded path that must be encoded in a url.
+const PATH: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+/// The absolute url of a page (or stylesheet) `uri` served for `http_host`.
+/// `uri` is the decoded path, as given by the server. The scheme is
+/// irrelevant, objects are matched to the page by host.
+pub fn page_url(http_host: &str, uri: &str) -> Url {
+    let uri = utf8_percent_encode(uri, PATH).to_string();
+    let path = if uri.starts_with('/') { uri } else { format!("/{}", uri) };
+
+    Url::parse(&format!("http://{}{}", http_host, path))
+        .or_else(|_| Url::parse(&format!("http://localhost{}", path)))
+        .unwrap_or_else(|_| Url::parse("http://localhost/").unwrap())
+}
+
+/// Resolves `reference` against `base`, returning the (percent-decoded)
+/// path of the object if it is served by the host of `page`, None if it is
+/// external or invalid. The query and fragment are dropped.
+pub fn object_path(base: &Url, page: &Url, reference: &str) -> Option<String> {
+    let url = base.join(reference).ok()?;
+
+    let local = (url.scheme() == "http" || url.scheme() == "https")
+        && url.host_str().is_some_and(|host| page.host_str().is_some_and(|page_host| host.eq_ignore_ascii_case(page_host)));
+    if !local {
+        return None;
+    }
+    percent_decode_str(url.path()).decode_utf8().ok().map(String::from)
+}
+
+/// Adds the ALPaCA GET parameter, with the given target size, to the query
+/// of `uri` (which may be relative), keeping its fragment last.
+pub fn padded_uri(uri: &str, target_size: usize) -> String {
+    // the fragment starts at the first #, the query at the first ? before it
+    let (before, fragment) = match uri.find('#') {
+        Some(pos) => uri.split_at(pos),
+        None => (uri, ""),
+    };
+    let separator = match before.find('?') {
+        Some(pos) if pos + 1 == before.len() || before.ends_with('&') => "",
+        Some(_) => "&",
+        None => "?",
+    };
+    format!("{}{}alpaca-padding={}{}", before, separator, target_size, fragment)
+}
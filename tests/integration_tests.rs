@@ -1 +1,1217 @@
+extern crate alpaca;
+extern crate rand;
+extern crate encoding_rs;
+extern crate flate2;
+extern crate brotli;
+extern crate crc32fast;
 
+use std::{env, fs, process, ptr};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use encoding_rs::{Encoding, SHIFT_JIS, UTF_16LE, UTF_8, WINDOWS_1252};
+use alpaca::error::{self as errors, AlpacaError};
+use alpaca::ffi;
+use alpaca::compress::Compression;
+use alpaca::image::{self, ImageFormat};
+use alpaca::dom::{self, ObjectKind};
+use alpaca::pad::{self, PadReader};
+use alpaca::source::{PaddingSource, CsprngSource, MimicSource, SeededSource};
+use alpaca::config::{Config, HtmlPadding, MorphConfig, DeterministicConfig};
+use alpaca::morphing::{self, Morpher, RequestContext};
+use alpaca::resolver::{ObjectResolver, ResolvedObject, FsResolver, DocumentRoot};
+use alpaca::policy::{Pattern, Policy};
+use alpaca::cache::{ObjectCache, REVALIDATE_INTERVAL};
+use alpaca::css;
+use alpaca::uri;
+
+const DESIGN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/design");
+
+// A deterministic morpher: one object per page, sizes in multiples of 1000.
+fn morpher() -> Morpher {
+    Morpher::new(MorphConfig::Deterministic(DeterministicConfig { obj_num: 1, obj_size: 1000, max_obj_size: 10000 })).unwrap()
+}
+
+// The request of the sample page.
+fn sample_ctx() -> RequestContext<'static> {
+    RequestContext { root: DESIGN_DIR, uri: "/sample-site/index.html", http_host: "localhost", alias: 0 }
+}
+
+// The sample site page, with `extra` inserted at the start of its <head>.
+fn sample_page(extra: &str) -> String {
+    let html = fs::read_to_string(format!("{}/sample-site/index.html", DESIGN_DIR)).unwrap();
+    html.replacen("<head>", &format!("<head>{}", extra), 1)
+}
+
+// Morphs `html` served as `uri` of `http_host`, with the design directory as root.
+fn morph(html: &str, uri: &str, http_host: &str) -> String {
+    morph_with(html, uri, http_host, false)
+}
+
+fn morph_with(html: &str, uri: &str, http_host: &str, in_place: bool) -> String {
+    let mut morpher = morpher();
+    morpher.set_in_place(in_place);
+    let ctx = RequestContext { root: DESIGN_DIR, uri, http_host, alias: 0 };
+
+    String::from_utf8(morpher.morph_html(html, &ctx).unwrap().content).unwrap()
+}
+
+fn objects_morphed(morphed: &str) -> bool {
+    morphed.contains("main.css?alpaca-padding=") && morphed.contains("alpacas-in-a-field.jpg?alpaca-padding=")
+}
+
+// The target size that `morphed` requests for `uri`.
+fn target_of(morphed: &str, uri: &str) -> usize {
+    let query = morphed.split(&format!("{}?alpaca-padding=", uri)).nth(1).unwrap_or_else(|| panic!("{} is not morphed", uri));
+    query.split(|c: char| !c.is_ascii_digit()).next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn objects_relative_to_page() {
+    assert!(objects_morphed(&morph(&sample_page(""), "/sample-site/index.html", "localhost")));
+    assert!(!objects_morphed(&morph(&sample_page(""), "/other/index.html", "localhost")));
+}
+
+#[test]
+fn base_absolute_path() {
+    let html = sample_page(r#"<base href="/sample-site/">"#);
+    assert!(objects_morphed(&morph(&html, "/other/dir/index.html", "localhost")));
+}
+
+#[test]
+fn base_relative_path() {
+    let html = sample_page(r#"<base href="../">"#);
+    assert!(objects_morphed(&morph(&html, "/sample-site/sub/index.html", "localhost")));
+}
+
+#[test]
+fn base_same_host_url() {
+    let html = sample_page(r#"<base href="http://localhost:8080/sample-site/index.html">"#);
+    assert!(objects_morphed(&morph(&html, "/other/index.html", "LOCALHOST")));
+}
+
+#[test]
+fn base_other_host_url() {
+    for base in &["http://cdn.example.com/sample-site/", "//cdn.example.com/sample-site/"] {
+        let html = sample_page(&format!(r#"<base href="{}">"#, base));
+        assert!(!objects_morphed(&morph(&html, "/sample-site/index.html", "localhost")));
+    }
+}
+
+#[test]
+fn first_base_is_used() {
+    let html = sample_page(r#"<base href="/sample-site/"><base href="/other/">"#);
+    assert!(objects_morphed(&morph(&html, "/index.html", "localhost")));
+}
+
+#[test]
+fn fragments_and_percent_encoding() {
+    let html = sample_page("").replace(r#"src="alpacas-in-a-field.jpg""#, r#"src="alpacas%2Din-a-field.jpg#view""#);
+    let morphed = morph(&html, "/sample-site/index.html", "localhost");
+    assert!(morphed.contains(r#"src="alpacas%2Din-a-field.jpg?alpaca-padding="#));
+    assert!(morphed.contains(r#"#view""#));
+}
+
+#[test]
+fn protocol_relative_is_external() {
+    let html = sample_page("").replace(r#"href="main.css""#, r#"href="//localhost.cdn/sample-site/main.css""#);
+    assert!(!morph(&html, "/sample-site/index.html", "localhost").contains("main.css?alpaca-padding="));
+}
+
+#[test]
+fn doctype_is_kept() {
+    for &in_place in &[false, true] {
+        assert!(morph_with(&sample_page(""), "/sample-site/index.html", "localhost", in_place).starts_with("<!DOCTYPE html>"));
+    }
+}
+
+#[test]
+fn in_place_keeps_markup() {
+    let html = sample_page("<STYLE>p { background: url('alpacas-in-a-field.jpg') }</STYLE>")
+        .replace(r#"<img src="alpacas-in-a-field.jpg" width="700">"#, "<IMG width=700 SRC=alpacas-in-a-field.jpg?v=1&amp;x >");
+    let morphed = morph_with(&html, "/sample-site/index.html", "localhost", true);
+    let content = &morphed[..morphed.rfind("<!--").unwrap()];
+
+    assert!(objects_morphed(content));
+    assert!(content.starts_with("<!DOCTYPE html>\n<html>\n\n<head><STYLE>p { background: url('alpacas-in-a-field.jpg?alpaca-padding="));
+    assert!(content.contains(r#"<IMG width=700 SRC="alpacas-in-a-field.jpg?v=1&amp;x&amp;alpaca-padding="#));
+    assert!(content.contains(r#"<meta name="viewport" content="width=device-width initial-scale=1">"#));
+    assert!(content.contains("<!-- Hidden abstract block -->"));
+    assert!(content.contains(r#"<link href="data:," rel="shortcut icon"></head>"#));
+
+    // nothing else changed (no fake images are needed with obj_num 1)
+    assert!(content.len() - html.len() < 150);
+}
+
+#[test]
+fn in_place_falls_back_to_serializing() {
+    // the parser moves the <div> out of the table
+    let html = sample_page("").replace("<body>", "<body><table><div>x</div></table>");
+    let morphed = morph_with(&html, "/sample-site/index.html", "localhost", true);
+    assert!(objects_morphed(&morphed));
+    assert!(morphed.contains("<div>x</div><table></table>"));
+}
+
+#[test]
+fn html_padding_placement() {
+    let raw = r#"<script>var b = "<b>";</script><textarea><i>x</i></textarea>"#;
+    let html = sample_page("").replace("<body>", &format!("<body>{}", raw));
+    let ctx = sample_ctx();
+
+    for &in_place in &[false, true] {
+        for name in &["end", "head", "body", "split", "div", "attribute"] {
+            let mut morpher = morpher();
+            morpher.set_in_place(in_place);
+            morpher.set_html_padding(HtmlPadding::from_name(name).unwrap());
+            let page = morpher.morph_html(&html, &ctx).unwrap();
+            assert_eq!(page.content.len(), page.target_size);
+            let morphed = String::from_utf8(page.content).unwrap();
+            // nothing is inserted in raw text
+            assert!(objects_morphed(&morphed) && morphed.contains(r#""<b>";</script>"#), "{}", name);
+            assert!(morphed.contains("x</i></textarea>") || morphed.contains("x&lt;/i&gt;</textarea>"), "{}", name);
+
+            let placed = match *name {
+                "end" => morphed.ends_with("-->"),
+                "head" => morphed.contains("--></head>"),
+                "body" => morphed.contains("--></body>"),
+                "split" => morphed.contains("<!--"),
+                "div" => morphed.contains("<div hidden>") && morphed.contains("</div></body>"),
+                _ => morphed.contains("<body data-"),
+            };
+            assert!(placed, "{}", name);
+        }
+    }
+    match Config::parse("mode = \"deterministic\"\n[deterministic]\nobj_num = 1\nobj_size = 1000\nmax_obj_size = 10000\n[html]\npadding = \"tail\"\n", "t.toml", Path::new(".")) {
+        Err(AlpacaError::Config(msg)) => assert!(msg.contains("t.toml:7")),
+        _ => panic!("unknown placements are refused"),
+    }
+}
+
+#[test]
+fn morpher_api() {
+    let morpher = morpher();
+    let page = morpher.morph_html(&sample_page(""), &sample_ctx()).unwrap();
+    assert_eq!(page.content.len(), page.target_size);
+    assert_eq!(page.target_size % 1000, 0);
+    assert!(objects_morphed(std::str::from_utf8(&page.content).unwrap()));
+
+    // objects are padded to the size of their query, with or without a morpher
+    let padding = morpher.morph_object("image/png", 1234, "v=1&alpaca-padding=3000").unwrap();
+    assert_eq!(padding.len(), 3000 - 1234);
+    assert_eq!(morphing::morph_object("text/css", 10, "alpaca-padding=100").unwrap().len(), 90);
+
+    match morpher.into_config().morph {
+        MorphConfig::Deterministic(ref config) => assert_eq!(config.obj_size, 1000),
+        _ => panic!("the configuration is kept"),
+    }
+}
+
+#[test]
+fn errors_and_warnings() {
+    // a missing object is left unchanged, the page is still morphed
+    let html = sample_page("").replace("<body>", r#"<body><img src="missing.png">"#);
+    let page = morpher().morph_html(&html, &sample_ctx()).unwrap();
+    assert!(objects_morphed(std::str::from_utf8(&page.content).unwrap()));
+    assert_eq!(page.warnings.len(), 1);
+    assert_eq!(page.warnings[0].code(), errors::ALPACA_ERR_IO);
+
+    match morpher().morph_object("image/png", 2000, "alpaca-padding=1000") {
+        Err(e) => assert_eq!(e.code(), errors::ALPACA_ERR_PADDING_UNDERFLOW),
+        Ok(_) => panic!("objects are not truncated"),
+    }
+
+    // through C: the error of the last call, and the warnings of the info
+    unsafe {
+        let config = ffi::alpaca_config_new(false, ptr::null(), ptr::null(), ptr::null(), false, 1, 1000, 10000);
+        let info = ffi::alpaca_morph_info_new(ffi::ALPACA_ABI_VERSION);
+        let (root, uri, host) = (CString::new(DESIGN_DIR).unwrap(), CString::new("/sample-site/index.html").unwrap(), CString::new("localhost").unwrap());
+        ffi::alpaca_morph_info_set_request(info, root.as_ptr(), uri.as_ptr(), host.as_ptr(), 0);
+        ffi::alpaca_morph_info_set_content(info, html.as_ptr(), html.len());
+        let mut out = ffi::AlpacaBuffer { data: ptr::null_mut(), len: 0, capacity: 0 };
+
+        assert_eq!(ffi::morph_html(config, info, &mut out), 1);
+        assert_eq!(ffi::alpaca_last_error_code(), 0);
+        assert!(ffi::alpaca_last_error_message().is_null());
+        assert_eq!(ffi::alpaca_morph_info_warning_count(info), 1);
+        assert!(CStr::from_ptr(ffi::alpaca_morph_info_warning(info, 0)).to_str().unwrap().contains("missing.png"));
+        assert!(ffi::alpaca_morph_info_warning(info, 1).is_null());
+        ffi::alpaca_buffer_free(&mut out);
+
+        let (query, content_type) = (CString::new("alpaca-padding=10").unwrap(), CString::new("image/png").unwrap());
+        ffi::alpaca_morph_info_set_object(info, query.as_ptr(), content_type.as_ptr());
+        assert_eq!(ffi::morph_object(config, info, &mut out), 0);
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_PADDING_UNDERFLOW);
+        assert!(!ffi::alpaca_last_error_message().is_null());
+        assert!(out.data.is_null());
+
+        ffi::alpaca_morph_info_free(info);
+        ffi::alpaca_config_free(config);
+    }
+}
+
+// A C resolver callback that panics, as Rust callbacks may.
+unsafe extern "C-unwind" fn panicking_resolver(_: *mut c_void, _: *const c_char, _: *const c_char, _: *const c_char, _: *mut ffi::AlpacaResolvedObject) -> c_int {
+    panic!("resolver bug");
+}
+
+#[test]
+fn panics_are_caught() {
+    let html = sample_page("");
+    unsafe {
+        let config = ffi::alpaca_config_new(false, ptr::null(), ptr::null(), ptr::null(), false, 1, 1000, 10000);
+        ffi::alpaca_config_set_resolver(config, Some(panicking_resolver), ptr::null_mut());
+        let info = ffi::alpaca_morph_info_new(ffi::ALPACA_ABI_VERSION);
+        let (root, uri, host) = (CString::new(DESIGN_DIR).unwrap(), CString::new("/sample-site/index.html").unwrap(), CString::new("localhost").unwrap());
+        ffi::alpaca_morph_info_set_request(info, root.as_ptr(), uri.as_ptr(), host.as_ptr(), 0);
+        ffi::alpaca_morph_info_set_content(info, html.as_ptr(), html.len());
+        let mut out = ffi::AlpacaBuffer { data: ptr::null_mut(), len: 0, capacity: 0 };
+
+        // the panic fails the call, the output is left untouched
+        assert_eq!(ffi::morph_html(config, info, &mut out), 0);
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_PANIC);
+        assert!(CStr::from_ptr(ffi::alpaca_last_error_message()).to_str().unwrap().contains("resolver bug"));
+        assert!(out.data.is_null());
+
+        // the former panicking paths fail instead
+        ffi::alpaca_morph_info_set_request(info, ptr::null(), uri.as_ptr(), host.as_ptr(), 0);
+        assert_eq!(ffi::morph_html(config, info, &mut out), 0);
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_INVALID_ARGUMENT);
+        let unknown = CString::new("Unknown(1)").unwrap();
+        assert!(ffi::alpaca_config_new(true, unknown.as_ptr(), unknown.as_ptr(), unknown.as_ptr(), false, 0, 0, 0).is_null());
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_DISTRIBUTION);
+
+        ffi::alpaca_morph_info_free(info);
+        ffi::alpaca_config_free(config);
+    }
+}
+
+#[test]
+fn abi_version() {
+    assert_eq!(ffi::alpaca_abi_version(), ffi::ALPACA_ABI_VERSION);
+    assert!(ffi::alpaca_morph_info_new(ffi::ALPACA_ABI_VERSION - 1).is_null());
+    assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_INVALID_ARGUMENT);
+
+    // the build fails when the committed header is stale
+    let header = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/include/alpaca.h")).unwrap();
+    assert!(header.contains(&format!("#define ALPACA_ABI_VERSION {}", ffi::ALPACA_ABI_VERSION)));
+}
+
+// An allocator handing out the memory of the Vec<u8> at `ctx`, if large enough.
+unsafe extern "C-unwind" fn vec_allocator(ctx: *mut c_void, size: usize) -> *mut u8 {
+    let pool = &mut *(ctx as *mut Vec<u8>);
+    if size <= pool.len() { pool.as_mut_ptr() } else { ptr::null_mut() }
+}
+
+#[test]
+fn buffer_ownership() {
+    unsafe {
+        let config = ffi::alpaca_config_new(false, ptr::null(), ptr::null(), ptr::null(), false, 1, 1000, 10000);
+        let info = ffi::alpaca_morph_info_new(ffi::ALPACA_ABI_VERSION);
+        let (query, content_type) = (CString::new("alpaca-padding=300").unwrap(), CString::new("text/css").unwrap());
+        ffi::alpaca_morph_info_set_object(info, query.as_ptr(), content_type.as_ptr());
+        ffi::alpaca_morph_info_set_content(info, ptr::null(), 100);
+        let mut out = ffi::AlpacaBuffer { data: ptr::null_mut(), len: 0, capacity: 0 };
+
+        // library-owned, freed and reset by alpaca_buffer_free
+        assert_eq!(ffi::morph_object(config, info, &mut out), 1);
+        assert_eq!(out.len, 200);
+        assert!(!out.data.is_null() && out.capacity >= out.len);
+        ffi::alpaca_buffer_free(&mut out);
+        assert!(out.data.is_null() && out.len == 0 && out.capacity == 0);
+        ffi::alpaca_buffer_free(&mut out);
+        ffi::alpaca_buffer_free(ptr::null_mut());
+
+        // caller-owned, only reset
+        let mut pool = vec![0u8; 256];
+        ffi::alpaca_morph_info_set_allocator(info, Some(vec_allocator), &mut pool as *mut Vec<u8> as *mut c_void);
+        assert_eq!(ffi::morph_object(config, info, &mut out), 1);
+        assert_eq!((out.data, out.len, out.capacity), (pool.as_mut_ptr(), 200, 0));
+        ffi::alpaca_buffer_free(&mut out);
+        assert!(out.data.is_null());
+        assert!(pool[..200].starts_with(b"/*") && pool[..200].ends_with(b"*/"));
+
+        // a failing allocator fails the call
+        let large = CString::new("alpaca-padding=1000").unwrap();
+        ffi::alpaca_morph_info_set_object(info, large.as_ptr(), content_type.as_ptr());
+        assert_eq!(ffi::morph_object(config, info, &mut out), 0);
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_ALLOC);
+        assert!(out.data.is_null());
+
+        ffi::alpaca_morph_info_free(info);
+        ffi::alpaca_config_free(config);
+    }
+}
+
+#[test]
+fn config_handle() {
+    let html = sample_page("");
+    unsafe {
+        // invalid configurations are refused once, at creation
+        assert!(ffi::alpaca_config_new(false, ptr::null(), ptr::null(), ptr::null(), false, 1, 1000, 1500).is_null());
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_CONFIG);
+        assert!(ffi::alpaca_config_new(true, ptr::null(), ptr::null(), ptr::null(), false, 0, 0, 0).is_null());
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_INVALID_ARGUMENT);
+
+        let config = ffi::alpaca_config_new(false, ptr::null(), ptr::null(), ptr::null(), false, 1, 1000, 10000);
+        assert!(!config.is_null());
+        let (div, tail) = (CString::new("div").unwrap(), CString::new("tail").unwrap());
+        assert_eq!(ffi::alpaca_config_set_html_padding(config, div.as_ptr()), 1);
+        assert_eq!(ffi::alpaca_config_set_html_padding(config, tail.as_ptr()), 0);
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_INVALID_ARGUMENT);
+        assert_eq!(ffi::alpaca_config_set_in_place(ptr::null_mut(), true), 0);
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_INVALID_ARGUMENT);
+
+        // the handle is reused by the requests, with its settings
+        let info = ffi::alpaca_morph_info_new(ffi::ALPACA_ABI_VERSION);
+        let (root, uri, host) = (CString::new(DESIGN_DIR).unwrap(), CString::new("/sample-site/index.html").unwrap(), CString::new("localhost").unwrap());
+        ffi::alpaca_morph_info_set_request(info, root.as_ptr(), uri.as_ptr(), host.as_ptr(), 0);
+        ffi::alpaca_morph_info_set_content(info, html.as_ptr(), html.len());
+        for _ in 0..2 {
+            let mut out = ffi::AlpacaBuffer { data: ptr::null_mut(), len: 0, capacity: 0 };
+            assert_eq!(ffi::morph_html(config, info, &mut out), 1);
+            let morphed = std::str::from_utf8(std::slice::from_raw_parts(out.data, out.len)).unwrap();
+            assert!(out.len.is_multiple_of(1000) && objects_morphed(morphed) && morphed.contains("<div hidden>"));
+            ffi::alpaca_buffer_free(&mut out);
+        }
+
+        ffi::alpaca_morph_info_free(info);
+        ffi::alpaca_config_free(config);
+        ffi::alpaca_config_free(ptr::null_mut());
+    }
+}
+
+// The Config error of parsing `toml`, which must fail.
+fn config_error(toml: &str) -> String {
+    match Config::parse(toml, "t.toml", Path::new(".")) {
+        Err(AlpacaError::Config(msg)) => msg,
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("invalid configuration accepted"),
+    }
+}
+
+#[test]
+fn config_file_errors() {
+    let config = Config::load(Path::new(DESIGN_DIR).join("alpaca.toml")).unwrap();
+    assert_eq!(config.roots.len(), 2);
+    assert_eq!(config.policies.policies().len(), 2);
+
+    let deterministic = "mode = \"deterministic\"\n[deterministic]\nobj_num = 1\nobj_size = 1000\n";
+    assert!(config_error(&format!("{}max_obj_size = 10000\ncolor = 1\n", deterministic)).starts_with("t.toml:6: unknown field `color`"));
+    assert!(config_error(&format!("{}max_obj_size = 1500\n", deterministic)).starts_with("t.toml:2:"));
+    assert!(config_error(&format!("{}max_obj_size = \"large\"\n", deterministic)).starts_with("t.toml:5:"));
+    assert!(config_error("\nmode = \"random\"\n").starts_with("t.toml:2:"));
+    assert!(config_error("mode = \"probabilistic\"\n").contains("no [probabilistic] section"));
+    assert!(config_error("mode = \"probabilistic\"\n[probabilistic]\ndist_html_size = \"Normal/1\"\ndist_obj_num = \"Poisson/1\"\ndist_obj_size = \"Normal/1,1\"\n").starts_with("t.toml:3:"));
+    assert!(config_error(&format!("{}max_obj_size = 10000\n\n[[override]]\nmode = \"deterministic\"\n", deterministic)).starts_with("t.toml:7:"));
+    // the limit must allow an empty page and the largest padding objects
+    for max_target_size in [0, 5000] {
+        let msg = config_error(&format!("{}max_obj_size = 10000\n\n[limits]\nmax_target_size = {}\n", deterministic, max_target_size));
+        assert!(msg.starts_with("t.toml:8: max_target_size"), "{}", msg);
+    }
+    let mut config = Config::new(MorphConfig::Deterministic(DeterministicConfig { obj_num: 1, obj_size: 1000, max_obj_size: 10000 }));
+    config.limits.max_target_size = 9999;
+    assert!(Morpher::from_config(config).is_err());
+
+    unsafe {
+        let missing = CString::new(format!("{}/missing.toml", DESIGN_DIR)).unwrap();
+        assert!(ffi::alpaca_config_load(missing.as_ptr()).is_null());
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_IO);
+
+        let path = env::temp_dir().join(format!("alpaca-config-{}.toml", process::id()));
+        fs::write(&path, "mode = \"deterministic\"\n\n[deterministic]\nobj_num = 0\nobj_size = 1000\nmax_obj_size = 1000\n").unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert!(ffi::alpaca_config_load(c_path.as_ptr()).is_null());
+        assert_eq!(ffi::alpaca_last_error_code(), errors::ALPACA_ERR_CONFIG);
+        let msg = CStr::from_ptr(ffi::alpaca_last_error_message()).to_str().unwrap();
+        assert!(msg.contains(&format!("{}:3:", path.display())), "{}", msg);
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn policy_specificity() {
+    let policy = |host: Option<&str>, path: Option<&str>, obj_size: usize| Policy {
+        host: host.map(Pattern::new),
+        path: path.map(Pattern::new),
+        morph: MorphConfig::Deterministic(DeterministicConfig { obj_num: 1, obj_size, max_obj_size: obj_size }),
+    };
+    let mut config = Config::new(MorphConfig::Deterministic(DeterministicConfig { obj_num: 1, obj_size: 1, max_obj_size: 1 }));
+    config.policies.add(policy(None, Some("/static/"), 2));
+    config.policies.add(policy(None, Some("/static/img/"), 3));
+    config.policies.add(policy(None, Some("/static/*.css"), 4));
+    config.policies.add(policy(Some("*.example.com"), None, 5));
+    config.policies.add(policy(Some("*.example.com"), Some("/blog/*"), 6));
+    config.policies.add(policy(Some("www.example.com"), Some("/blog/*"), 7));
+    config.policies.add(policy(None, Some("/static/img/"), 8));
+    config.policies.add(policy(Some("*example.com"), None, 9));
+    config.policies.add(policy(Some("example.com"), None, 10));
+
+    let obj_size = |host: &str, uri: &str| match *config.morph_config_for(host, uri) {
+        MorphConfig::Deterministic(ref d) => d.obj_size,
+        _ => unreachable!(),
+    };
+    assert_eq!(obj_size("localhost", "/index.html"), 1);
+    assert_eq!(obj_size("localhost", "/static/a.js"), 2);
+    // the longest path wins, then the first added
+    assert_eq!(obj_size("localhost", "/static/img/a.png"), 3);
+    assert_eq!(obj_size("localhost", "/static/a.css"), 4);
+    // globs match the whole uri, prefixes its start
+    assert_eq!(obj_size("localhost", "/static/a.css.map"), 2);
+    // a host wins over any path, exact hosts over globs; hosts ignore case and ports
+    assert_eq!(obj_size("cdn.example.com", "/static/img/a.png"), 5);
+    assert_eq!(obj_size("CDN.Example.com:8080", "/blog/post"), 6);
+    assert_eq!(obj_size("www.example.com:443", "/blog/post"), 7);
+    // even over globs as long, added before
+    assert_eq!(obj_size("example.com", "/blog/post"), 10);
+    assert_eq!(obj_size("[::1]:80", "/static/a.js"), 2);
+}
+
+// A resolver of generated objects, recording the references it is asked for.
+struct GeneratedResolver {
+    references: Arc<Mutex<Vec<String>>>,
+}
+
+impl ObjectResolver for GeneratedResolver {
+    fn resolve(&self, _: &RequestContext, reference: &str) -> Result<Option<ResolvedObject>, AlpacaError> {
+        self.references.lock().unwrap().push(String::from(reference));
+        match reference {
+            "/site/gen/a b.png" => Ok(Some(ResolvedObject { size: 1500, kind: None, path: None, css_refs: 0 })),
+            "/site/gen/err.png" => Err(AlpacaError::Sampling(String::from("generator down"))),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[test]
+fn custom_resolver() {
+    let html = r#"<html><head></head><body><img src="gen/a%20b.png"><img src="/gen/none.png"><img src="//other.org/x.png"><img src="gen/err.png?v=1#f"></body></html>"#;
+    let references = Arc::new(Mutex::new(Vec::new()));
+    let mut morpher = morpher();
+    morpher.set_resolver(Some(Box::new(GeneratedResolver { references: references.clone() })));
+    let ctx = RequestContext { root: "/nonexistent", uri: "/site/index.html", http_host: "localhost", alias: 0 };
+    let page = morpher.morph_html(html, &ctx).unwrap();
+    let morphed = String::from_utf8(page.content).unwrap();
+
+    // absolute, decoded references without query, never of other hosts
+    assert_eq!(*references.lock().unwrap(), ["/site/gen/a b.png", "/gen/none.png", "/site/gen/err.png"]);
+    assert!(morphed.contains(r#"src="gen/a%20b.png?alpaca-padding=2000""#), "{}", morphed);
+    assert!(morphed.contains(r#"src="/gen/none.png""#) && morphed.contains(r#"src="//other.org/x.png""#));
+    assert_eq!(page.warnings.len(), 1);
+    assert!(page.warnings[0].to_string().contains("generator down"));
+
+    // the default resolver is restored
+    morpher.set_resolver(None);
+    assert!(objects_morphed(&String::from_utf8(morpher.morph_html(&sample_page(""), &sample_ctx()).unwrap().content).unwrap()));
+}
+
+#[test]
+fn objects_sized_from_metadata() {
+    let root = traversal_site("metadata");
+    // a sparse file is never read, a FIFO never opened (which would block)
+    fs::File::create(root.join("big.WOFF2")).unwrap().set_len(50_000_000).unwrap();
+    let fifo = process::Command::new("mkfifo").arg(root.join("pipe.png")).status().is_ok_and(|s| s.success());
+
+    let ctx = RequestContext { root: root.to_str().unwrap(), uri: "/index.html", http_host: "localhost", alias: 0 };
+    let big = FsResolver::new().resolve(&ctx, "/big.WOFF2").unwrap().unwrap();
+    assert_eq!(big.size, 50_000_000);
+    assert_eq!(big.kind, Some(ObjectKind::Font));
+    assert_eq!(big.path, Some(root.join("big.WOFF2")));
+    let png = FsResolver::new().resolve(&ctx, "/dir/b.png").unwrap().unwrap();
+    assert_eq!((png.size, png.kind), (200, Some(ObjectKind::IMG)));
+    if fifo {
+        match FsResolver::new().resolve(&ctx, "/pipe.png") {
+            Err(e) => assert_eq!(e.code(), errors::ALPACA_ERR_IO),
+            Ok(_) => panic!("only regular files are objects"),
+        }
+    }
+
+    let html = r#"<html><head></head><body><object data="big.WOFF2"></object><img src="dir/b.png"><img src="pipe.png"></body></html>"#;
+    let morpher = Morpher::new(MorphConfig::Deterministic(DeterministicConfig { obj_num: 2, obj_size: 1000, max_obj_size: 100_000_000 })).unwrap();
+    let page = morpher.morph_html(html, &ctx).unwrap();
+    let morphed = String::from_utf8(page.content).unwrap();
+    let target = target_of(&morphed, "big.WOFF2");
+    assert!(target > 50_000_000 && target.is_multiple_of(1000));
+    assert!(morphed.contains("dir/b.png?alpaca-padding=1000"));
+    assert_eq!(page.warnings.len(), fifo as usize);
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn object_cache() {
+    let root = traversal_site("cache");
+    let (a, b) = (root.join("a.png"), root.join("dir/b.png"));
+    let object = |size| ResolvedObject { size, kind: Some(ObjectKind::IMG), path: None, css_refs: 0 };
+
+    let cache = ObjectCache::new();
+    assert!(cache.get(&a).is_none());
+    cache.insert(&a, &object(100), &fs::metadata(&a).unwrap());
+    cache.insert(&b, &object(200), &fs::metadata(&b).unwrap());
+    assert_eq!(cache.get(&a).unwrap().size, 100);
+
+    // changes are seen once the entry is revalidated, or invalidated
+    fs::write(&a, [0u8; 150]).unwrap();
+    fs::write(&b, [0u8; 250]).unwrap();
+    assert_eq!(cache.get(&a).unwrap().size, 100);
+    cache.invalidate(Some(&b));
+    assert!(cache.get(&b).is_none());
+    std::thread::sleep(REVALIDATE_INTERVAL + Duration::from_millis(100));
+    assert!(cache.get(&a).is_none());
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (2, 3, 0));
+
+    cache.insert(&a, &object(150), &fs::metadata(&a).unwrap());
+    cache.invalidate(None);
+    assert_eq!(cache.stats().entries, 0);
+
+    // the global cache of FsResolver, through C
+    let ctx = RequestContext { root: root.to_str().unwrap(), uri: "/index.html", http_host: "localhost", alias: 0 };
+    let size = || FsResolver::new().resolve(&ctx, "/a.png").unwrap().unwrap().size;
+    unsafe {
+        let mut stats = ffi::AlpacaCacheStats { hits: 0, misses: 0, entries: 0 };
+        assert_eq!(size(), 150);
+        assert_eq!(size(), 150);
+        assert_eq!(ffi::alpaca_cache_stats(&mut stats), 1);
+        assert!(stats.hits >= 1 && stats.misses >= 1 && stats.entries >= 1);
+        assert_eq!(ffi::alpaca_cache_stats(ptr::null_mut()), 0);
+
+        fs::write(&a, [0u8; 50]).unwrap();
+        let path = CString::new(a.to_str().unwrap()).unwrap();
+        ffi::alpaca_cache_invalidate(path.as_ptr());
+        assert_eq!(size(), 50);
+    }
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn css_references() {
+    let css = r#"@import "a.css"; @IMPORT url(b.css) screen;
+/* url(comment.png) */ p { background: URL( c.png ) }
+q { background: url('d e.png#x'), url(data:image/png;base64,AA==), url(#grad) }
+r { content: "url(string.png)"; background: url("f\").png") } s { background: myurl(g.png) }
+"#;
+    let refs = css::scan_refs(css);
+    let found: Vec<(&str, bool)> = refs.iter().map(|r| (r.uri.as_str(), r.import)).collect();
+    assert_eq!(found, [("a.css", true), ("b.css", true), ("c.png", false), ("d e.png#x", false)]);
+    assert!(refs.iter().all(|r| css[r.range.clone()] == r.uri));
+
+    let rewritten = css::rewrite(css, &[(&refs[0], String::from("A")), (&refs[2], String::from("C"))]);
+    assert_eq!(rewritten, css.replace("\"a.css\"", "\"A\"").replace("( c.png )", "( C )"));
+    assert_eq!(css::rewrite_overhead(refs.len(), 99999), 4 * (16 + 5));
+}
+
+#[test]
+fn css_in_html() {
+    let html = sample_page("<style>body { background: url(alpacas-in-a-field.jpg) }</style>")
+        .replace("<body>", r#"<body><div style="background: url('index.php'); color: red">x</div>"#);
+
+    for &in_place in &[false, true] {
+        let morphed = morph_with(&html, "/sample-site/index.html", "localhost", in_place);
+        let (image, php) = (target_of(&morphed, "alpacas-in-a-field.jpg"), target_of(&morphed, "index.php"));
+        assert!(morphed.contains(&format!("<style>body {{ background: url(alpacas-in-a-field.jpg?alpaca-padding={}) }}</style>", image)), "{}", morphed);
+        assert!(morphed.contains(&format!(r#"<div style="background: url('index.php?alpaca-padding={}'); color: red">x</div>"#, php)), "{}", morphed);
+    }
+}
+
+#[test]
+fn morph_css_end_to_end() {
+    let root = traversal_site("css");
+    let style = "@import \"sub/b.css\";\nbody { background: url(a.png) }\n";
+    let imported = "p { background: url(../dir/b.png) }\n";
+    fs::write(root.join("style.css"), style).unwrap();
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub/b.css"), imported).unwrap();
+
+    let morpher = morpher();
+    let ctx = |uri| RequestContext { root: root.to_str().unwrap(), uri, http_host: "localhost", alias: 0 };
+    let html = r#"<html><head><link rel="stylesheet" href="style.css"></head><body></body></html>"#;
+    let page = String::from_utf8(morpher.morph_html(html, &ctx("/index.html")).unwrap().content).unwrap();
+
+    // the stylesheet grows with its references, and is padded to the target of the page
+    let target = target_of(&page, "style.css");
+    let morphed = morpher.morph_css(style, &ctx("/style.css"), &format!("v=2&alpaca-padding={}", target)).unwrap();
+    assert!(morphed.warnings.is_empty());
+    assert_eq!((morphed.content.len(), morphed.target_size), (target, target));
+    let morphed = String::from_utf8(morphed.content).unwrap();
+    assert!(morphed.starts_with("@import \"sub/b.css?alpaca-padding=") && morphed.contains("url(a.png?alpaca-padding=1000)"), "{}", morphed);
+
+    let target = target_of(&morphed, "sub/b.css");
+    let morphed = morpher.morph_css(imported, &ctx("/sub/b.css"), &format!("alpaca-padding={}", target)).unwrap();
+    assert_eq!(morphed.content.len(), target);
+    assert!(String::from_utf8(morphed.content).unwrap().starts_with("p { background: url(../dir/b.png?alpaca-padding=1000) }\n/*"));
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+// Size of the generated stylesheet of css_resolver.
+const GENERATED_CSS_SIZE: usize = 996;
+
+// A resolver of generated objects, reporting the number of references of the
+// stylesheet stored at ctx.
+unsafe extern "C-unwind" fn css_resolver(ctx: *mut c_void, _: *const c_char, _: *const c_char, reference: *const c_char, out: *mut ffi::AlpacaResolvedObject) -> c_int {
+    match CStr::from_ptr(reference).to_str().unwrap() {
+        "/gen/style.css" => {
+            (*out).size = GENERATED_CSS_SIZE;
+            (*out).content_type = b"text/css\0".as_ptr() as *const c_char;
+            (*out).css_refs = *(ctx as *const usize);
+        },
+        _ => (*out).size = 500,
+    }
+    1
+}
+
+// Runs one of the morphing calls of the C interface, returning its output.
+unsafe fn c_morph(f: unsafe extern "C" fn(*const ffi::AlpacaConfig, *const ffi::MorphInfo, *mut ffi::AlpacaBuffer) -> u8, config: *const ffi::AlpacaConfig, info: *const ffi::MorphInfo) -> Vec<u8> {
+    let mut out = ffi::AlpacaBuffer { data: ptr::null_mut(), len: 0, capacity: 0 };
+    assert_eq!(f(config, info, &mut out), 1, "{:?}", CStr::from_ptr(ffi::alpaca_last_error_message()));
+    let content = std::slice::from_raw_parts(out.data, out.len).to_vec();
+    ffi::alpaca_buffer_free(&mut out);
+    content
+}
+
+#[test]
+fn css_of_custom_resolver() {
+    let body = "a { background: url(a.png) } b { background: url(b.png) }";
+    let style = format!("{}{}", body, " ".repeat(GENERATED_CSS_SIZE - body.len()));
+    let html = r#"<html><head><link rel="stylesheet" href="/gen/style.css"></head><body></body></html>"#;
+    let (root, uri, css_uri, host) = (CString::new("/srv").unwrap(), CString::new("/index.html").unwrap(), CString::new("/gen/style.css").unwrap(), CString::new("localhost").unwrap());
+    let content_type = CString::new("text/css").unwrap();
+
+    // with the references counted, room is reserved for them; otherwise
+    // those that don't fit are left unchanged instead of failing
+    for &(css_refs, rewritten) in &[(2usize, true), (0, false)] {
+        unsafe {
+            let config = ffi::alpaca_config_new(false, ptr::null(), ptr::null(), ptr::null(), false, 1, 1000, 10000);
+            ffi::alpaca_config_set_resolver(config, Some(css_resolver), &css_refs as *const usize as *mut c_void);
+            let info = ffi::alpaca_morph_info_new(ffi::ALPACA_ABI_VERSION);
+            ffi::alpaca_morph_info_set_request(info, root.as_ptr(), uri.as_ptr(), host.as_ptr(), 0);
+            ffi::alpaca_morph_info_set_content(info, html.as_ptr(), html.len());
+            let page = String::from_utf8(c_morph(ffi::morph_html, config, info)).unwrap();
+            let target = target_of(&page, "/gen/style.css");
+
+            let query = CString::new(format!("alpaca-padding={}", target)).unwrap();
+            ffi::alpaca_morph_info_set_request(info, root.as_ptr(), css_uri.as_ptr(), host.as_ptr(), 0);
+            ffi::alpaca_morph_info_set_object(info, query.as_ptr(), content_type.as_ptr());
+            ffi::alpaca_morph_info_set_content(info, style.as_ptr(), style.len());
+            let morphed = String::from_utf8(c_morph(ffi::morph_css, config, info)).unwrap();
+            assert_eq!(morphed.len(), target);
+            assert_eq!(morphed.contains("url(a.png?alpaca-padding=1000)") && morphed.contains("url(b.png?alpaca-padding=1000)"), rewritten);
+            assert_eq!(ffi::alpaca_morph_info_warning_count(info), if rewritten { 0 } else { 2 });
+
+            ffi::alpaca_morph_info_free(info);
+            ffi::alpaca_config_free(config);
+        }
+    }
+}
+
+#[test]
+fn srcset_candidates() {
+    let urls = |srcset: &'static str| -> Vec<&'static str> { dom::srcset_urls(srcset).into_iter().map(|range| &srcset[range]).collect() };
+    assert_eq!(urls("a.png 1x, b.png 2x"), ["a.png", "b.png"]);
+    assert_eq!(urls("  a.png 480w,b.png   800w ,c.png"), ["a.png", "b.png", "c.png"]);
+    assert_eq!(urls("data:image/png;base64,AA,BB 1x, b.png 2x"), ["data:image/png;base64,AA,BB", "b.png"]);
+    assert_eq!(urls("a.png,, b.png,"), ["a.png", "b.png"]);
+    assert_eq!(urls("a.png (x, y) 1x, b.png"), ["a.png", "b.png"]);
+    assert!(urls("").is_empty() && urls(" , ,").is_empty());
+}
+
+#[test]
+fn media_elements() {
+    let root = traversal_site("media");
+    let html = r#"<html><head></head><body>
+<picture><source srcset="dir/b.png 1x, a.png?v=1 2x,"><img src="a.png" srcset="data:image/png;base64,AA,BB 1x, dir/b.png 2x"></picture>
+<video poster="dir/b.png" src="a.png"></video><object data="a.png"></object>
+<input src="dir/b.png" type="IMAGE"><input src="a.png"><source srcset="a.png"></body></html>"#;
+    let ctx = RequestContext { root: root.to_str().unwrap(), uri: "/index.html", http_host: "localhost", alias: 0 };
+
+    for &in_place in &[false, true] {
+        let mut morpher = morpher();
+        morpher.set_in_place(in_place);
+        let morphed = String::from_utf8(morpher.morph_html(html, &ctx).unwrap().content).unwrap();
+        for expected in &[
+            r#"<source srcset="dir/b.png?alpaca-padding=1000 1x, a.png?v=1&amp;alpaca-padding=1000 2x,">"#,
+            r#"<img src="a.png?alpaca-padding=1000" srcset="data:image/png;base64,AA,BB 1x, dir/b.png?alpaca-padding=1000 2x">"#,
+            r#"<video poster="dir/b.png?alpaca-padding=1000" src="a.png?alpaca-padding=1000">"#,
+            r#"<object data="a.png?alpaca-padding=1000">"#,
+            r#"<input src="dir/b.png?alpaca-padding=1000" type="IMAGE"><input src="a.png"><source srcset="a.png">"#,
+        ] {
+            assert!(morphed.contains(expected), "{}: {}", expected, morphed);
+        }
+    }
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+// Morphs the sample page encoded in `encoding`, with `extra` inserted in its
+// <head> and a paragraph of `text`. Returns the page and the encoded paragraph.
+fn morph_encoded(encoding: &'static Encoding, extra: &str, content_type: Option<&str>, text: &str) -> Result<(Vec<u8>, Vec<u8>), AlpacaError> {
+    let html = sample_page(extra).replace(r#"<meta charset="utf-8">"#, "").replace(r#"content="text/html; charset=UTF-8""#, "")
+        .replace("<body>", &format!("<body><p>{}</p>", text));
+    let (content, _, _) = encoding.encode(&html);
+    let mut morpher = morpher();
+    morpher.set_in_place(true);     // keeps the NUL bytes
+    let ctx = sample_ctx();
+
+    let page = morpher.morph_html_bytes(&content, content_type, &ctx)?;
+    assert_eq!(page.content.len(), page.target_size);
+    let expected = encoding.encode(&format!("<p>{}</p>", text)).0.into_owned();
+    Ok((page.content, expected))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn legacy_encodings() {
+    let cases = [
+        (WINDOWS_1252, r#"<meta charset="iso-8859-1">"#, None, "Café, naïve, 10€"),
+        (SHIFT_JIS, "", Some("text/html; charset=Shift_JIS"), "アルパカ"),
+        (SHIFT_JIS, r#"<meta http-equiv="Content-Type" content="text/html; charset=sjis">"#, None, "アルパカ"),
+        (UTF_8, "", Some("text/html"), "Alpaka\0\u{e9}"),
+    ];
+    for &(encoding, extra, content_type, text) in &cases {
+        let (morphed, expected) = morph_encoded(encoding, extra, content_type, text).unwrap();
+        assert!(contains(&morphed, &expected), "{}", encoding.name());
+        assert!(contains(&morphed, b"main.css?alpaca-padding="));
+    }
+    match morph_encoded(UTF_16LE, "", Some("text/html; charset=utf-16le"), "x") {
+        Err(AlpacaError::Encoding(_)) => {},
+        _ => panic!("UTF-16 pages cannot be padded"),
+    }
+}
+
+fn decompress(compression: Compression, content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match compression {
+        Compression::Gzip => flate2::read::GzDecoder::new(content).read_to_end(&mut out),
+        Compression::Deflate => flate2::read::ZlibDecoder::new(content).read_to_end(&mut out),
+        Compression::Brotli => brotli::Decompressor::new(content, 4096).read_to_end(&mut out),
+    }.unwrap();
+    out
+}
+
+#[test]
+fn compressed_sizes() {
+    let morpher = morpher();
+    let ctx = sample_ctx();
+    let html = sample_page("");
+    let object: Vec<u8> = (0..70000).map(|i| (i % 7) as u8).collect();
+
+    for &compression in &[Compression::Gzip, Compression::Deflate, Compression::Brotli] {
+        let page = morpher.morph_html_compressed(html.as_bytes(), None, Some(compression), &ctx).unwrap();
+        assert_eq!(page.content.len(), page.target_size);
+        assert_eq!(page.target_size % 1000, 0);
+        let decompressed = String::from_utf8(decompress(compression, &page.content)).unwrap();
+        assert!(objects_morphed(&decompressed));
+        assert!(decompressed.ends_with("-->"));
+
+        // an object spanning several stored blocks
+        for &target_size in &[5000, 150000] {
+            let padded = morpher.morph_object_compressed("application/octet-stream", &object, &format!("alpaca-padding={}", target_size), compression).unwrap();
+            assert_eq!(padded.len(), target_size);
+            assert!(decompress(compression, &padded).starts_with(&object));
+        }
+        match morpher.morph_object_compressed("application/octet-stream", &object, "alpaca-padding=10", compression) {
+            Err(AlpacaError::PaddingUnderflow { .. }) => {},
+            _ => panic!("the compressed object exceeds the target size"),
+        }
+
+        // binary objects are padded with raw bytes, images not at all (only
+        // their container can hold padding)
+        let target_size = compression.min_size(&object, 0).unwrap() + 5;
+        let padded = morpher.morph_object_compressed("application/octet-stream", &object, &format!("alpaca-padding={}", target_size), compression).unwrap();
+        assert_eq!(padded.len(), target_size);
+        let decompressed = decompress(compression, &padded);
+        assert!(decompressed.starts_with(&object) && decompressed.len() <= object.len() + 6);
+        match morpher.morph_object_compressed("image/png", PNG, "alpaca-padding=1000", compression) {
+            Err(AlpacaError::InvalidArgument(_)) => {},
+            _ => panic!("compressed images cannot be padded in their container"),
+        }
+        let css = b"p { color: red }";
+        let target_size = compression.min_size(css, 4).unwrap();
+        let padded = morpher.morph_object_compressed("text/css", css, &format!("alpaca-padding={}", target_size), compression).unwrap();
+        assert_eq!(decompress(compression, &padded), b"p { color: red }/**/");
+        assert!(morpher.morph_object_compressed("text/css", css, &format!("alpaca-padding={}", target_size - 1), compression).is_err());
+    }
+}
+
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x01\x00\x00\x00\x01\x08\x00\x00\x00\x00\x3a\x7e\x9b\x55\
+    \x00\x00\x00\x0aIDAT\x78\x9c\x63\x60\x00\x00\x00\x02\x00\x01\x48\xaf\xa4\x71\x00\x00\x00\x00IEND\xae\x42\x60\x82";
+const GIF: &[u8] = b"GIF87a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\x3b";
+const WEBP: &[u8] = b"RIFF\x1a\x00\x00\x00WEBPVP8L\x0d\x00\x00\x00\x2f\x00\x00\x00\x10\x07\x10\x11\x11\x88\x88\xfe\x07\x00";
+
+// The chunk types of a png, checking their crc.
+fn png_chunks(png: &[u8]) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut pos = 8;
+    while pos < png.len() {
+        let len = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]) as usize;
+        let crc = &png[pos + 8 + len..pos + 12 + len];
+        assert_eq!(crc32fast::hash(&png[pos + 4..pos + 8 + len]).to_be_bytes(), crc);
+        chunks.push(String::from_utf8_lossy(&png[pos + 4..pos + 8]).into_owned());
+        pos += len + 12;
+    }
+    chunks
+}
+
+#[test]
+fn image_padding() {
+    let jpeg = fs::read(format!("{}/sample-site/alpacas-in-a-field.jpg", DESIGN_DIR)).unwrap();
+    assert_eq!(ImageFormat::detect(&jpeg), Some(ImageFormat::Jpeg));
+
+    for &pad_len in &[26, 27, 1000, 100001] {
+        let png = image::pad_image(PNG, PNG.len() + pad_len, &CsprngSource).unwrap();
+        assert_eq!(png.len(), PNG.len() + pad_len);
+        assert_eq!(png_chunks(&png), ["IHDR", "IDAT", "alPa", "IEND"]);
+
+        let padded = image::pad_image(&jpeg, jpeg.len() + pad_len, &CsprngSource).unwrap();
+        assert_eq!(padded.len(), jpeg.len() + pad_len);
+        assert!(padded[..20] == jpeg[..20] && padded.ends_with(&[0xFF, 0xD9]));    // APP0 first, EOI last
+        assert!(contains(&padded, &[0xFF, 0xFE]));
+
+        let gif = image::pad_image(GIF, GIF.len() + pad_len, &CsprngSource).unwrap();
+        assert_eq!(gif.len(), GIF.len() + pad_len);
+        assert!(gif.starts_with(b"GIF89a") && gif.ends_with(b"\x00\x3b"));
+        assert_eq!(&gif[GIF.len() - 1..GIF.len() + 1], b"\x21\xfe");
+
+        // webp files cannot have an odd size
+        match image::pad_image(WEBP, WEBP.len() + pad_len, &CsprngSource) {
+            Ok(webp) => {
+                assert!(pad_len % 2 == 0 && webp.len() == WEBP.len() + pad_len);
+                let riff_size = u32::from_le_bytes([webp[4], webp[5], webp[6], webp[7]]) as usize;
+                assert_eq!(riff_size + 8, webp.len());
+                assert_eq!(&webp[12..16], b"VP8X");
+            },
+            Err(AlpacaError::InvalidArgument(_)) => assert!(pad_len % 2 == 1),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // unknown formats are padded at their end
+    let padded = image::pad_image(b"not an image", 100, &CsprngSource).unwrap();
+    assert!(padded.len() == 100 && padded.starts_with(b"not an image"));
+    match image::pad_image(PNG, PNG.len() + 11, &CsprngSource) {
+        Err(AlpacaError::PaddingUnderflow { .. }) => {},
+        _ => panic!("a png chunk needs 12 bytes"),
+    }
+}
+
+#[test]
+fn webp_targets_are_even() {
+    let root = traversal_site("webp");
+    fs::write(root.join("a.webp"), WEBP).unwrap();
+    let html = r#"<html><head></head><body><img src="a.webp"><img src="a.png"></body></html>"#;
+    let ctx = RequestContext { root: root.to_str().unwrap(), uri: "/index.html", http_host: "localhost", alias: 0 };
+
+    // an odd object size is doubled for webp images only
+    let morpher = Morpher::new(MorphConfig::Deterministic(DeterministicConfig { obj_num: 1, obj_size: 1001, max_obj_size: 10010 })).unwrap();
+    let morphed = String::from_utf8(morpher.morph_html(html, &ctx).unwrap().content).unwrap();
+    assert_eq!((target_of(&morphed, "a.webp"), target_of(&morphed, "a.png")), (2002, 1001));
+
+    let query = format!("alpaca-padding={}", target_of(&morphed, "a.webp"));
+    let webp = morpher.morph_object_content("image/webp", WEBP, &query).unwrap();
+    assert_eq!(webp.len(), 2002);
+    assert_eq!(ImageFormat::detect(&webp), Some(ImageFormat::WebP));
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn object_kinds() {
+    let kinds = [
+        ("text/javascript; charset=utf-8", ObjectKind::JS),
+        ("application/javascript", ObjectKind::JS),
+        ("image/svg+xml", ObjectKind::SVG),
+        ("image/png", ObjectKind::IMG),
+        ("font/woff2", ObjectKind::Font),
+        ("application/ld+json", ObjectKind::JSON),
+        ("application/wasm", ObjectKind::Wasm),
+        ("Application/PDF", ObjectKind::PDF),
+        ("application/octet-stream", ObjectKind::Unknown),
+    ];
+    for &(mime, kind) in &kinds {
+        assert_eq!(dom::parse_object_kind(mime), kind, "{}", mime);
+    }
+    assert_eq!(dom::path_object_kind(Path::new("/fonts/a.WOFF2")), Some(ObjectKind::Font));
+    assert_eq!(dom::path_object_kind(Path::new("/index.php")), None);
+}
+
+#[test]
+fn object_padding() {
+    let svg = pad::pad_object_content(ObjectKind::SVG, b"<svg/>", 100, &CsprngSource).unwrap();
+    assert!(svg.len() == 100 && svg.starts_with(b"<svg/><!--") && svg.ends_with(b"-->"));
+
+    let json = pad::pad_object_content(ObjectKind::JSON, b"{}", 100, &CsprngSource).unwrap();
+    assert!(json.len() == 100 && json[2..].iter().all(|b| b" \t\r\n".contains(b)));
+
+    // a custom section, with a 5-byte size
+    let wasm = pad::pad_object_content(ObjectKind::Wasm, b"\0asm\x01\0\0\0", 1008, &CsprngSource).unwrap();
+    assert_eq!(wasm.len(), 1008);
+    assert_eq!(&wasm[8..21], b"\x00\xe2\x87\x80\x80\x00\x06alpaca");
+
+    // comments before startxref, the offsets are unchanged
+    let pdf: &[u8] = b"%PDF-1.4\n...\ntrailer\n<< /Root 1 0 R >>\nstartxref\n9\n%%EOF\n";
+    let padded = pad::pad_object_content(ObjectKind::PDF, pdf, 1000, &CsprngSource).unwrap();
+    assert_eq!(padded.len(), 1000);
+    assert!(padded.starts_with(&pdf[..39]) && padded.ends_with(&pdf[39..]));
+    assert!(padded[39..padded.len() - 19].split(|&b| b == b'\n').all(|line| line.is_empty() || (line[0] == b'%' && line.len() < 255)));
+
+    // the private data block of a WOFF font, 4-byte aligned
+    let mut woff = b"wOFF".to_vec();
+    woff.resize(45, 0);
+    woff[8..12].copy_from_slice(&45u32.to_be_bytes());
+    let padded = pad::pad_object_content(ObjectKind::Font, &woff, 100, &CsprngSource).unwrap();
+    assert_eq!(padded.len(), 100);
+    assert_eq!(&padded[8..12], &100u32.to_be_bytes());
+    assert_eq!(&padded[36..44], &[0, 0, 0, 48, 0, 0, 0, 52]);
+
+    match pad::pad_object_content(ObjectKind::Wasm, b"\0asm\x01\0\0\0", 20, &CsprngSource) {
+        Err(AlpacaError::PaddingUnderflow { .. }) => {},
+        _ => panic!("a wasm section needs 13 bytes"),
+    }
+}
+
+// The sum of the big-endian words of an sfnt font or table.
+fn sfnt_checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, w| sum.wrapping_add(u32::from_be_bytes([w[0], w[1], w[2], w[3]])))
+}
+
+#[test]
+fn font_padding() {
+    // a TrueType font with a cmap and a head table
+    let mut ttf = b"\x00\x01\x00\x00\x00\x02\x00\x20\x00\x01\x00\x00".to_vec();
+    for (tag, offset, length) in [(b"cmap", 44u32, 8u32), (b"head", 52, 56)] {
+        ttf.extend_from_slice(tag);
+        ttf.extend_from_slice(&[0; 4]);
+        ttf.extend_from_slice(&offset.to_be_bytes());
+        ttf.extend_from_slice(&length.to_be_bytes());
+    }
+    ttf.resize(108, 7);
+    let padded = pad::pad_object_content(ObjectKind::Font, &ttf, 1000, &CsprngSource).unwrap();
+    assert_eq!(padded.len(), 1000);
+
+    // the binary search fields, then the records sorted by tag, the tables
+    // moved by the new record
+    assert_eq!(&padded[..12], b"\x00\x01\x00\x00\x00\x03\x00\x20\x00\x01\x00\x10");
+    let records: Vec<(&[u8], u32, u32)> = padded[12..60].chunks(16).map(|r| {
+        (&r[..4], u32::from_be_bytes([r[8], r[9], r[10], r[11]]), u32::from_be_bytes([r[12], r[13], r[14], r[15]]))
+    }).collect();
+    assert_eq!(records, [(&b"alPa"[..], 124, 876), (&b"cmap"[..], 60, 8), (&b"head"[..], 68, 56)]);
+    assert!(padded[60..76] == ttf[44..60] && padded[80..124] == ttf[64..]);
+    assert_eq!(&padded[16..20], &sfnt_checksum(&padded[124..]).to_be_bytes());
+    // the checksum adjustment of the head table
+    assert_eq!(sfnt_checksum(&padded), 0xB1B0AFBA);
+
+    // unknown formats and collections are padded at their end
+    for font in [&b"ttcf\x00\x01\x00\x00"[..], b"????"] {
+        let padded = pad::pad_object_content(ObjectKind::Font, font, 100, &CsprngSource).unwrap();
+        assert!(padded.len() == 100 && padded.starts_with(font));
+    }
+    match pad::pad_object_content(ObjectKind::Font, &ttf, 120, &CsprngSource) {
+        Err(AlpacaError::PaddingUnderflow { .. }) => {},
+        _ => panic!("a table needs 20 bytes"),
+    }
+
+    // without their content, fonts are padded at their end, like images
+    let morpher = morpher();
+    assert_eq!(morpher.morph_object("font/woff2", 100, "alpaca-padding=1000").unwrap().len(), 900);
+    let reader = morpher.morph_object_reader("font/woff", 100, "alpaca-padding=1000").unwrap();
+    assert_eq!(reader.chunks(0).map(|chunk| chunk.len()).sum::<usize>(), 900);
+    assert!(morpher.morph_object("font/ttf", 990, "alpaca-padding=1000").is_err());
+    match morpher.morph_object_compressed("font/ttf", &ttf, "alpaca-padding=1000", Compression::Gzip) {
+        Err(AlpacaError::InvalidArgument(_)) => {},
+        _ => panic!("compressed fonts cannot be padded in their container"),
+    }
+}
+
+#[test]
+fn padding_sources() {
+    // the same seed gives the same paddings
+    let padded: Vec<Vec<u8>> = (0..2).map(|_| {
+        let mut morpher = morpher();
+        morpher.set_padding_source(Box::new(SeededSource::new(42)));
+        morpher.morph_object_content("text/css", b"a{}", "alpaca-padding=500").unwrap()
+    }).collect();
+    assert_eq!(padded[0], padded[1]);
+    assert_eq!(padded[0].len(), 500);
+
+    for len in [0, 1, 7, 1000] {
+        let mut text = vec![0; len];
+        MimicSource.fill_text(&mut text);
+        assert!(text.iter().all(|&b| (b' '..=b'~').contains(&b) && !b"<>&\"'*-".contains(&b)));
+        CsprngSource.fill_text(&mut text);
+        assert!(text.iter().all(u8::is_ascii_alphanumeric));
+    }
+}
+
+// A source of constant paddings, whatever they are split into.
+struct ConstantSource;
+
+impl PaddingSource for ConstantSource {
+    fn fill_bytes(&self, out: &mut [u8]) {
+        out.iter_mut().for_each(|b| *b = 0xab);
+    }
+
+    fn fill_text(&self, out: &mut [u8]) {
+        out.iter_mut().for_each(|b| *b = b'x');
+    }
+}
+
+#[test]
+fn padding_in_chunks() {
+    let kinds = [ObjectKind::CSS, ObjectKind::SVG, ObjectKind::JSON, ObjectKind::Wasm, ObjectKind::PDF, ObjectKind::IMG];
+    for &kind in &kinds {
+        for &target_size in &[100, 101, 1000, 70000] {
+            let padding = pad::get_object_padding(kind, 50, target_size, &ConstantSource).unwrap();
+            for &chunk_size in &[1, 7, 256, 100000] {
+                let reader = PadReader::new(kind, 50, target_size, &ConstantSource as &dyn PaddingSource).unwrap();
+                let chunks: Vec<Vec<u8>> = reader.chunks(chunk_size).collect();
+                assert!(chunks.iter().rev().skip(1).all(|c| c.len() == chunk_size));
+                assert_eq!(chunks.concat(), padding, "{:?} {} {}", kind, target_size, chunk_size);
+            }
+        }
+    }
+
+    let morpher = morpher();
+    let mut reader = morpher.morph_object_reader("text/javascript", 100, "alpaca-padding=100000").unwrap();
+    assert_eq!(reader.remaining(), 99900);
+    let mut padding = Vec::new();
+    reader.read_to_end(&mut padding).unwrap();
+    assert!(padding.len() == 99900 && padding.starts_with(b"/*") && padding.ends_with(b"*/"));
+    assert_eq!(reader.remaining(), 0);
+    assert!(morpher.morph_object_reader("text/javascript", 100, "alpaca-padding=102").is_err());
+
+    // chunks of the default size, not none
+    let reader = morpher.morph_object_reader("text/javascript", 100, "alpaca-padding=20000").unwrap();
+    let chunks: Vec<Vec<u8>> = reader.chunks(0).collect();
+    assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [pad::DEFAULT_CHUNK_SIZE, pad::DEFAULT_CHUNK_SIZE, 19900 - 2 * pad::DEFAULT_CHUNK_SIZE]);
+}
+
+// Creates <tmp>/<name>/{root,outside}, with symlinks from the root to
+// outside. Returns the canonical root.
+fn traversal_site(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("alpaca-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("root/dir")).unwrap();
+    fs::create_dir_all(dir.join("outside")).unwrap();
+    fs::write(dir.join("root/a.png"), [0u8; 100]).unwrap();
+    fs::write(dir.join("root/dir/b.png"), [0u8; 200]).unwrap();
+    fs::write(dir.join("outside/secret.png"), [0u8; 300]).unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::symlink;
+        symlink("../outside", dir.join("root/link-out")).unwrap();
+        symlink("../outside/secret.png", dir.join("root/file-out")).unwrap();
+        symlink("dir", dir.join("root/link-in")).unwrap();
+    }
+    fs::canonicalize(dir.join("root")).unwrap()
+}
+
+fn resolve(root: &Path, uri: &str, http_host: &str, alias: usize, reference: &str) -> Option<PathBuf> {
+    let ctx = RequestContext { root: root.to_str().unwrap(), uri, http_host, alias };
+    FsResolver::new().resolve(&ctx, reference).ok().and_then(|res| res.and_then(|res| res.path))
+}
+
+#[test]
+fn resolution_stays_in_root() {
+    let root = traversal_site("confined");
+
+    assert_eq!(resolve(&root, "/index.html", "h", 0, "/a.png"), Some(root.join("a.png")));
+    assert_eq!(resolve(&root, "/dir/index.html", "h", 0, "../../../a.png"), Some(root.join("a.png")));
+    assert_eq!(resolve(&root, "/index.html", "h", 0, "/../outside/secret.png"), None);
+    if cfg!(unix) {
+        assert_eq!(resolve(&root, "/index.html", "h", 0, "/link-in/b.png"), Some(root.join("dir/b.png")));
+        assert_eq!(resolve(&root, "/index.html", "h", 0, "/link-out/secret.png"), None);
+        assert_eq!(resolve(&root, "/index.html", "h", 0, "/file-out"), None);
+
+        // files cached under a parent root are not found through the cache
+        let parent = root.parent().unwrap();
+        assert_eq!(resolve(parent, "/index.html", "h", 0, "/root/link-out/secret.png"), Some(parent.join("outside/secret.png")));
+        assert_eq!(resolve(&root, "/index.html", "h", 0, "/link-out/secret.png"), None);
+    }
+}
+
+#[test]
+fn host_cannot_escape_root() {
+    let root = traversal_site("host");
+    let templated = format!("{}/$http_host", root.parent().unwrap().display());
+
+    for host in &["root", "..", ".", "", "root/../outside", "../outside"] {
+        let ctx = RequestContext { root: &templated, uri: "/index.html", http_host: host, alias: 0 };
+        let found = FsResolver::new().resolve(&ctx, "/secret.png").ok().and_then(|res| res.and_then(|res| res.path));
+        assert_eq!(found, None, "host {:?}", host);
+    }
+}
+
+#[test]
+fn alias_prefixes_do_not_panic() {
+    let root = traversal_site("alias");
+
+    assert_eq!(resolve(&root, "/static/index.html", "h", 8, "/static/a.png"), Some(root.join("a.png")));
+    assert_eq!(resolve(&root, "/static/index.html", "h", 8, "/other/a.png"), None);
+    for alias in 0..20 {
+        resolve(&root, "/é/ü/index.html", "h", alias, "x/a.png");
+        resolve(&root, "", "h", alias, "a.png");
+    }
+}
+
+// Random references, built from path segments meant to escape the root,
+// never resolve to a file outside of it.
+#[test]
+fn random_references_stay_in_root() {
+    let root = traversal_site("random");
+    let segments = [
+        "..", ".", "", "a.png", "dir", "b.png", "link-out", "file-out", "link-in", "outside", "secret.png",
+        "%2e%2e", "%2F", "..%2f..", "\\..", "é", "\0", "?", "#", "//", "http:", "root",
+    ];
+    let pages = ["/index.html", "/dir/index.html", "/é/index.html", "", "/"];
+    let mut rng = StdRng::seed_from_u64(0x616c70616361);
+    let mut resolved = 0;
+
+    for _ in 0..5000 {
+        let n = rng.gen_range(1, 8);
+        let mut reference: Vec<&str> = (0..n).map(|_| segments[rng.gen_range(0, segments.len())]).collect();
+        if rng.gen() {
+            reference.insert(0, "");
+        }
+        let reference = reference.join("/");
+        let page = pages[rng.gen_range(0, pages.len())];
+        let alias = rng.gen_range(0, 4);
+
+        // both as given, and as resolved from a page through its url
+        let page_url = uri::page_url("h", page);
+        let decoded = uri::object_path(&page_url, &page_url, &reference);
+
+        for candidate in Some(reference.clone()).iter().chain(decoded.iter()) {
+            if let Some(found) = resolve(&root, page, "h", alias, candidate) {
+                assert!(found.starts_with(&root), "{:?} resolved to {:?}", candidate, found);
+                assert!(!found.ends_with("secret.png"));
+                resolved += 1;
+            }
+        }
+    }
+    assert!(resolved > 0);
+}
+
+#[test]
+fn document_root_table() {
+    let site = format!("{}/sample-site", DESIGN_DIR);
+    let resolver = FsResolver::with_roots(vec![
+        DocumentRoot { prefix: String::from("/static/"), path: site.clone(), alias: true },
+        DocumentRoot { prefix: String::from("/static/design/"), path: String::from(DESIGN_DIR), alias: true },
+        DocumentRoot { prefix: String::from("/sample-site/"), path: String::from(DESIGN_DIR), alias: false },
+    ]);
+    let ctx = RequestContext { root: "/nonexistent", uri: "/index.html", http_host: "localhost", alias: 0 };
+    let size = |reference| resolver.resolve(&ctx, reference).ok().and_then(|res| res.map(|res| res.size));
+    let css_size = fs::metadata(format!("{}/main.css", site)).unwrap().len() as usize;
+
+    assert_eq!(size("/static/main.css"), Some(css_size));
+    assert_eq!(size("/static/design/sample-site/main.css"), Some(css_size));
+    assert_eq!(size("/sample-site/main.css"), Some(css_size));
+    assert_eq!(size("/static/../sample-site/main.css"), Some(css_size));
+    assert_eq!(size("/static/../main.css"), None);
+    assert_eq!(size("/other/main.css"), None);
+}